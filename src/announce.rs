@@ -0,0 +1,79 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use teloxide::prelude::*;
+use teloxide::types::{ChatId, MessageId, ThreadId};
+use crate::process::ProcessOptions;
+use crate::retry::Retry;
+use crate::store::Store;
+use crate::telegram;
+use crate::template::{self, Markup};
+use crate::youtube::VideoMetadata;
+use crate::Platform;
+
+// A message with the links of all copies of a video, sent once the last upload has finished.
+// Nothing is announced unless one of the templates is set.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct AnnounceConfig {
+    // Text of the announcement, e.g. "New video: {title}{#if vk_url} | VK: {vk_url}{/if}"
+    pub template: Option<String>,
+    pub parse_mode: Markup,
+    // Chats the announcement is sent to; empty means `--chat-id`
+    pub chats: Vec<AnnounceChat>,
+    // Appended to the caption of the Telegram post, e.g. "VK: {vk_url}"
+    pub caption: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct AnnounceChat {
+    pub chat_id: i64,
+    // Topic of a forum group
+    pub message_thread_id: Option<i32>,
+}
+
+// The video is out already, so a failed announcement is only reported
+pub(crate) async fn announce(options: &ProcessOptions, metadata: &VideoMetadata, url: &str) {
+    if let Err(e) = try_announce(options, metadata, url).await {
+        eprintln!("Failed to announce the video: {:#}", e);
+    }
+}
+
+async fn try_announce(options: &ProcessOptions, metadata: &VideoMetadata, url: &str) -> Result<()> {
+    let config = &options.config.announce;
+    if config.template.is_none() && config.caption.is_none() {
+        return Ok(());
+    }
+    let mut vars = template::variables(metadata, url);
+    vars.extend(Store::load(&options.config.store)?.links(&metadata.id));
+
+    if let Some(caption) = &config.caption {
+        telegram::update_posts(&options.config, &options.upload, &metadata.id, &vars, Some(caption)).await;
+    }
+
+    let Some(template) = &config.template else {
+        return Ok(());
+    };
+    let text = template::render(template, &vars, Some(4096), config.parse_mode)?;
+    let chats = match (&config.chats[..], options.upload.chat_id) {
+        ([], Some(chat_id)) => vec![AnnounceChat { chat_id, message_thread_id: None }],
+        ([], None) => bail!("No chat to announce to, set announce.chats or --chat-id"),
+        (chats, _) => chats.to_vec(),
+    };
+
+    let bot = telegram::bot(&options.upload)?;
+    let retry = Retry { platform: Platform::Telegram, policy: options.config.retry.policy(Platform::Telegram), job: None };
+    for chat in &chats {
+        retry.run("Sending the announcement", || async {
+            let mut request = bot.send_message(ChatId(chat.chat_id), &text);
+            if let Some(thread) = chat.message_thread_id {
+                request = request.message_thread_id(ThreadId(MessageId(thread)));
+            }
+            if let Some(mode) = telegram::parse_mode(config.parse_mode) {
+                request = request.parse_mode(mode);
+            }
+            Ok(request.await?)
+        }).await?;
+    }
+    println!("Announced the video in {} chat(s)", chats.len());
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Body, Client, Method, StatusCode, Url};
+use serde::Deserialize;
+use crate::progress;
+use crate::retry::{check_status, Retry};
+use crate::template;
+use crate::youtube::VideoMetadata;
+
+// The video is stored under a path rendered from `template`, with the thumbnail and a JSON file of
+// its metadata next to it. Each file appears under its final name only once it is complete.
+#[derive(Deserialize, Clone)]
+pub(crate) struct FolderConfig {
+    // Root directory, e.g. a NAS mount
+    pub path: String,
+    #[serde(default = "default_template")]
+    pub template: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct WebdavConfig {
+    // Folder on the share, e.g. `https://cloud.example.org/remote.php/dav/files/me/Videos`
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_template")]
+    pub template: String,
+}
+
+fn default_template() -> String {
+    "{uploader}/{upload_date}/{title}.mp4".to_string()
+}
+
+// Relative paths of the video, its thumbnail and its metadata sidecar
+struct Layout {
+    video: String,
+    thumbnail: Option<(String, String)>,
+    sidecar: String,
+}
+
+impl Layout {
+    fn new(template: &str, metadata: &VideoMetadata) -> Result<Layout> {
+        let video = template::render_path(template, &template::variables(metadata, &metadata.webpage_url))?;
+        let stem = match video.rsplit_once('.') {
+            Some((stem, _)) if !stem.ends_with('/') => stem.to_string(),
+            _ => video.clone(),
+        };
+        let thumbnail = metadata.thumbnail_file.as_ref().map(|file| {
+            let extension = Path::new(file).extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_else(|| "jpg".to_string());
+            (file.clone(), format!("{}.{}", stem, extension))
+        });
+        Ok(Layout { video, thumbnail, sidecar: format!("{}.json", stem) })
+    }
+}
+
+fn sidecar(metadata: &VideoMetadata) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(metadata)?)
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let part = part_path(path);
+    fs::write(&part, contents).context(format!("Failed to write {}", part.display()))?;
+    fs::rename(&part, path).context(format!("Failed to rename {}", part.display()))?;
+    Ok(())
+}
+
+// Returns the path of the stored video
+pub async fn upload_to_folder(config: &FolderConfig, file_path: &str, metadata: &VideoMetadata) -> Result<Option<String>> {
+    let layout = Layout::new(&config.template, metadata)?;
+    let target = Path::new(&config.path).join(&layout.video);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create directory: {}", parent.display()))?;
+    }
+    println!("Storing as {}", target.display());
+
+    let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+    let pb = progress::bytes(file_size, "Copying to the folder");
+    let part = part_path(&target);
+    let mut reader = progress::open(file_path, 0, file_size, &pb).await?;
+    let mut out = tokio::fs::File::create(&part).await.context(format!("Failed to create file: {}", part.display()))?;
+    tokio::io::copy(&mut reader, &mut out).await.context(format!("Failed to write {}", part.display()))?;
+    out.sync_all().await?;
+    fs::rename(&part, &target).context(format!("Failed to rename {}", part.display()))?;
+    pb.finish_with_message("Copy complete");
+
+    if let Some((thumbnail, path)) = &layout.thumbnail {
+        let contents = fs::read(thumbnail).context(format!("Failed to read thumbnail: {}", thumbnail))?;
+        write_atomically(&Path::new(&config.path).join(path), &contents)?;
+    }
+    write_atomically(&Path::new(&config.path).join(&layout.sidecar), &sidecar(metadata)?)?;
+
+    Ok(Some(target.to_string_lossy().to_string()))
+}
+
+struct Share<'a> {
+    client: Client,
+    config: &'a WebdavConfig,
+    base: Url,
+    retry: &'a Retry<'a>,
+}
+
+impl Share<'_> {
+    fn url(&self, path: &str) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut().expect("an http URL has a path").pop_if_empty().extend(path.split('/'));
+        url
+    }
+
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+        self.client.request(method, url).basic_auth(&self.config.username, Some(&self.config.password))
+    }
+
+    // Creates the folders above `path` one level at a time, as WebDAV requires
+    async fn create_parents(&self, path: &str) -> Result<()> {
+        let parts: Vec<&str> = path.split('/').collect();
+        for depth in 1..parts.len() {
+            let url = self.url(&parts[..depth].join("/"));
+            self.retry.run("Creating a folder", || async {
+                let res = self.request(Method::from_bytes(b"MKCOL")?, url.clone()).send().await?;
+                // 405 means the folder is there already
+                if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+                    check_status(res).await?;
+                }
+                Ok(())
+            }).await?;
+        }
+        Ok(())
+    }
+
+    // Uploads under a temporary name and moves the file into place once it is complete
+    async fn put<F>(&self, path: &str, what: &str, body: impl Fn() -> F) -> Result<()>
+    where F: std::future::Future<Output = Result<(Body, u64)>> {
+        let part = self.url(&format!("{}.part", path));
+        self.retry.run(what, || async {
+            let (body, len) = body().await?;
+            let res = self.request(Method::PUT, part.clone())
+                .header("Content-Length", len)
+                .body(body)
+                .send()
+                .await?;
+            check_status(res).await?;
+            Ok(())
+        }).await?;
+        self.retry.run("Renaming the upload", || async {
+            let res = self.request(Method::from_bytes(b"MOVE")?, part.clone())
+                .header("Destination", self.url(path).as_str())
+                .header("Overwrite", "T")
+                .send()
+                .await?;
+            check_status(res).await?;
+            Ok(())
+        }).await
+    }
+}
+
+// Returns the URL of the stored video on the share
+pub async fn upload_to_webdav(config: &WebdavConfig, file_path: &str, metadata: &VideoMetadata,
+                              retry: &Retry<'_>) -> Result<Option<String>> {
+    let base = Url::parse(&config.url).context(format!("Invalid WebDAV URL: {}", config.url))?;
+    if base.cannot_be_a_base() {
+        return Err(anyhow!("Invalid WebDAV URL: {}", config.url));
+    }
+    let share = Share { client: Client::new(), config, base, retry };
+    let layout = Layout::new(&config.template, metadata)?;
+    share.create_parents(&layout.video).await?;
+    println!("Storing as {}", share.url(&layout.video));
+
+    let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+    let pb = progress::bytes(file_size, "Uploading to WebDAV");
+    share.put(&layout.video, "Uploading the video", || async {
+        let reader = progress::open(file_path, 0, file_size, &pb).await?;
+        Ok((progress::body(reader), file_size))
+    }).await?;
+    pb.finish_with_message("Upload complete");
+
+    if let Some((thumbnail, path)) = &layout.thumbnail {
+        let contents = fs::read(thumbnail).context(format!("Failed to read thumbnail: {}", thumbnail))?;
+        share.put(path, "Uploading the thumbnail", || async { Ok((Body::from(contents.clone()), contents.len() as u64)) }).await?;
+    }
+    let contents = sidecar(metadata)?;
+    share.put(&layout.sidecar, "Uploading the metadata", || async { Ok((Body::from(contents.clone()), contents.len() as u64)) }).await?;
+
+    Ok(Some(share.url(&layout.video).to_string()))
+}
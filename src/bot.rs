@@ -1,11 +1,17 @@
+use std::path::Path;
 use std::sync::Arc;
 use regex::Regex;
-use teloxide::{net, Bot, prelude::*, types::Message, utils::command::BotCommands};
+use teloxide::{Bot, prelude::*, types::Message, utils::command::BotCommands};
 use teloxide::dispatching::{Dispatcher, UpdateFilterExt};
 use teloxide::dptree::entry;
 use teloxide::requests::Requester;
-use crate::process;
-use crate::Platform;
+use crate::error::ErrorReport;
+use crate::jobs;
+use crate::process::{self, ProcessOptions};
+use crate::schedule::{self, PublishAt};
+use crate::sync;
+use crate::telegram;
+use crate::youtube::VideoMetadata;
 
 #[derive(BotCommands, Debug)]
 #[command(rename_rule = "lowercase", description = "These are the possible commands:")]
@@ -14,65 +20,73 @@ enum Command {
     Start,
     #[command(description = "Display help message")]
     Help,
+    #[command(description = "Schedule a YouTube link for the next free slots, or list scheduled posts")]
+    Schedule(String),
+    #[command(description = "Remove a scheduled post by its ID")]
+    Unschedule(String),
+    #[command(description = "Move a scheduled post to another position: /move <id> <position>", parse_with = "split")]
+    Move { id: u64, position: usize },
+    #[command(description = "Show recent jobs with their status and retries")]
+    Jobs,
 }
 
 // Shared configuration struct
 #[derive(Clone)]
 struct BotConfig {
-    platform: Platform,
-    output: String,
-    delete_youtube: bool,
-    delete_transformed: bool,
-    rutube_api_key: Option<String>,
-    bot_api_url: String,
-    max_file_size: u64,
-    bot_token: String,
-    chat_id: Option<i64>,
-    vk_access_token: Option<String>,
+    process_options: ProcessOptions,
     allowed_users: Vec<u64>,
 }
 
-pub(crate) async fn run(bot_token: &str,
-                        platform: Platform,
-                        output: &str,
-                        delete_youtube: bool,
-                        delete_transformed: bool,
-                        rutube_api_key: Option<String>,
-                        bot_api_url: &str,
-                        max_file_size: u64,
-                        chat_id: Option<i64>,
-                        vk_access_token: Option<String>,
-                        allowed_users: Vec<u64>,) -> anyhow::Result<()> {
-    let client = net::default_reqwest_settings()
-        .timeout(std::time::Duration::from_secs(240))
-        .build()
-        .expect("Client creation failed");
+pub(crate) async fn run(process_options: ProcessOptions, allowed_users: Vec<u64>) -> anyhow::Result<()> {
+    // Talks to the same Bot API server the uploads go through
+    let bot = telegram::bot(&process_options.upload)?;
+    telegram::check_server(&process_options.upload).await?;
 
-    let bot = Bot::with_client(bot_token, client);
+    let sync = &process_options.config.sync;
+    if !sync.sources.is_empty() {
+        println!("Mirroring {} source(s) every {} minute(s)", sync.sources.len(), sync.interval_minutes);
+        tokio::spawn(sync::run_forever(process_options.clone()));
+    }
+    tokio::spawn(schedule::run(process_options.clone()));
 
     let config = Arc::new(BotConfig {
-        platform,
-        output: output.to_string(),
-        delete_youtube,
-        delete_transformed,
-        rutube_api_key,
-        bot_api_url: bot_api_url.to_string(),
-        max_file_size,
-        bot_token: bot_token.to_string(),
-        chat_id,
-        vk_access_token,
+        process_options,
         allowed_users
     });
 
     async fn handle_message(bot: Bot, msg: Message, config: Arc<BotConfig>) -> ResponseResult<()> {
         let youtube_regex = Regex::new(r"(https?://)?(www\.)?(youtube\.com/(watch\?v=|shorts/)|youtu\.be/)[\w-]+").unwrap();
 
-        let user_id = msg.from().unwrap().id.0; // Extract the u64 value from UserId
+        let user_id = msg.from.as_ref().unwrap().id.0; // Extract the u64 value from UserId
         if !config.allowed_users.contains(&user_id) {
             bot.send_message(msg.chat.id, "You are not authorized to use this bot.").await?;
             return Ok(());
         }
 
+        if let Some((file_id, file_name, unique_id)) = incoming_video(&msg) {
+            bot.send_message(msg.chat.id, "Processing the video you sent...").await?;
+            let options = &config.process_options;
+            let result = async {
+                let path = telegram::download_file(&bot, &options.upload, &file_id, &file_name, &options.output).await?;
+                let title = msg.caption()
+                    .and_then(|caption| caption.lines().next())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| Path::new(&file_name).file_stem().unwrap_or_default().to_string_lossy().to_string());
+                let metadata = VideoMetadata {
+                    id: format!("telegram-{}", unique_id),
+                    title,
+                    filename: path,
+                    ..Default::default()
+                };
+                process::file(metadata, options).await
+            }.await;
+            match result {
+                Ok(()) => bot.send_message(msg.chat.id, "Video processed successfully!").await?,
+                Err(e) => bot.send_message(msg.chat.id, failure_reply(&e)).await?,
+            };
+            return Ok(());
+        }
+
         if let Some(text) = msg.text() {
             // Check if the message is a command
             if let Ok(command) = Command::parse(text, "my_bot") {
@@ -83,6 +97,42 @@ pub(crate) async fn run(bot_token: &str,
                     Command::Help => {
                         bot.send_message(msg.chat.id, Command::descriptions().to_string()).await?;
                     }
+                    Command::Schedule(arg) => {
+                        let options = &config.process_options;
+                        if let Some(mat) = youtube_regex.find(&arg) {
+                            bot.send_message(msg.chat.id, "Processing YouTube video for the schedule...").await?;
+                            let options = ProcessOptions { publish_at: Some(PublishAt::NextSlot), ..options.clone() };
+                            if let Err(e) = process::youtube(mat.as_str(), &options).await {
+                                bot.send_message(msg.chat.id, failure_reply(&e)).await?;
+                                return Ok(());
+                            }
+                        }
+                        bot.send_message(msg.chat.id, schedule_listing(&config)).await?;
+                    }
+                    Command::Unschedule(arg) => {
+                        let reply = match arg.trim().parse::<u64>() {
+                            Ok(id) => match schedule::unschedule(&config.process_options.config, id) {
+                                Ok(post) => format!("Removed #{} {}", post.id, post.metadata.title),
+                                Err(e) => format!("Error: {}", e),
+                            },
+                            Err(_) => "Usage: /unschedule <id>".to_string(),
+                        };
+                        bot.send_message(msg.chat.id, reply).await?;
+                    }
+                    Command::Move { id, position } => {
+                        match schedule::reorder(&config.process_options.config, id, position) {
+                            Ok(()) => bot.send_message(msg.chat.id, schedule_listing(&config)).await?,
+                            Err(e) => bot.send_message(msg.chat.id, format!("Error: {}", e)).await?,
+                        };
+                    }
+                    Command::Jobs => {
+                        let reply = match jobs::recent(&config.process_options.config, 10) {
+                            Ok(jobs) if jobs.is_empty() => "No jobs yet.".to_string(),
+                            Ok(jobs) => jobs.iter().map(jobs::describe).collect::<Vec<_>>().join("\n"),
+                            Err(e) => format!("Error: {}", e),
+                        };
+                        bot.send_message(msg.chat.id, reply).await?;
+                    }
                 }
             }
             // Extract and process the YouTube URL
@@ -92,20 +142,11 @@ pub(crate) async fn run(bot_token: &str,
                 let cfg = config.clone();
                 if let Err(e) = process::youtube(
                     youtube_link, // Pass only the YouTube link
-                    cfg.platform,
-                    &cfg.output,
-                    cfg.delete_youtube,
-                    cfg.delete_transformed,
-                    cfg.rutube_api_key.clone(),
-                    &cfg.bot_api_url,
-                    cfg.max_file_size,
-                    Some(cfg.bot_token.clone()),
-                    cfg.chat_id,
-                    cfg.vk_access_token.clone(),
+                    &cfg.process_options,
                 )
                     .await
                 {
-                    bot.send_message(msg.chat.id, format!("Error processing video: {}", e)).await?;
+                    bot.send_message(msg.chat.id, failure_reply(&e)).await?;
                 } else {
                     bot.send_message(msg.chat.id, "Video processed successfully!").await?;
                 }
@@ -116,6 +157,41 @@ pub(crate) async fn run(bot_token: &str,
         Ok(())
     }
 
+    // The short message of the error instead of the whole chain, with a hint whether sending the
+    // link again may help
+    fn failure_reply(error: &anyhow::Error) -> String {
+        let report = ErrorReport::new(error);
+        if report.retryable {
+            format!("Error processing video: {}. This looks temporary, send the link again later.", report)
+        } else {
+            format!("Error processing video: {}", report)
+        }
+    }
+
+    // File ID, file name and unique ID of a video sent as a video or as a file
+    fn incoming_video(msg: &Message) -> Option<(String, String, String)> {
+        if let Some(video) = msg.video() {
+            let name = video.file_name.clone().unwrap_or_else(|| format!("{}.mp4", video.file.unique_id));
+            return Some((video.file.id.clone(), name, video.file.unique_id.clone()));
+        }
+        let document = msg.document()?;
+        let is_video = document.mime_type.as_ref().is_some_and(|mime| mime.type_() == "video");
+        let name = document.file_name.clone().unwrap_or_else(|| format!("{}.mp4", document.file.unique_id));
+        is_video.then(|| (document.file.id.clone(), name, document.file.unique_id.clone()))
+    }
+
+    fn schedule_listing(config: &BotConfig) -> String {
+        let config = &config.process_options.config;
+        match schedule::list(config) {
+            Ok(posts) if posts.is_empty() => "Nothing is scheduled.".to_string(),
+            Ok(posts) => posts.iter()
+                .map(|post| schedule::describe(config, post))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
     let handler = entry()
         .branch(Update::filter_message().endpoint({
             let bot_config = config.clone();
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use crate::announce::AnnounceConfig;
+use crate::archive::{FolderConfig, WebdavConfig};
+use crate::discord::DiscordConfig;
+use crate::mastodon::MastodonConfig;
+use crate::matrix::MatrixConfig;
+use crate::ok::OkConfig;
+use crate::peertube::PeertubeConfig;
+use crate::retry::RetryConfig;
+use crate::s3::S3Config;
+use crate::schedule::ScheduleConfig;
+use crate::server::ServerConfig;
+use crate::telegram::TelegramConfig;
+use crate::template::TemplateConfig;
+use crate::titles::TitleConfig;
+use crate::webhooks::WebhooksConfig;
+use crate::youtube_api::YoutubeConfig;
+use crate::Platform;
+
+// Settings that do not fit on the command line, loaded from a JSON file
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct Config {
+    // Path of the store that keeps the publication history
+    pub store: String,
+    // Finished jobs kept in the store; older ones are dropped as new jobs start
+    pub job_history: usize,
+    pub sync: SyncConfig,
+    // Title, description and message templates per platform
+    pub templates: HashMap<Platform, TemplateConfig>,
+    pub titles: TitleConfig,
+    // Named sets of settings that a job can opt into with `--profile`
+    pub profiles: HashMap<String, ProfileConfig>,
+    pub schedule: ScheduleConfig,
+    pub retry: RetryConfig,
+    pub telegram: TelegramConfig,
+    pub announce: AnnounceConfig,
+    pub peertube: Option<PeertubeConfig>,
+    pub ok: Option<OkConfig>,
+    pub s3: Option<S3Config>,
+    pub folder: Option<FolderConfig>,
+    pub webdav: Option<WebdavConfig>,
+    pub youtube: Option<YoutubeConfig>,
+    pub discord: Option<DiscordConfig>,
+    pub mastodon: Option<MastodonConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub webhooks: WebhooksConfig,
+    pub server: ServerConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            store: "./store.json".to_string(),
+            job_history: 500,
+            sync: SyncConfig::default(),
+            templates: HashMap::new(),
+            titles: TitleConfig::default(),
+            profiles: HashMap::new(),
+            schedule: ScheduleConfig::default(),
+            retry: RetryConfig::default(),
+            telegram: TelegramConfig::default(),
+            announce: AnnounceConfig::default(),
+            peertube: None,
+            ok: None,
+            s3: None,
+            folder: None,
+            webdav: None,
+            youtube: None,
+            discord: None,
+            mastodon: None,
+            matrix: None,
+            webhooks: WebhooksConfig::default(),
+            server: ServerConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct SyncConfig {
+    pub interval_minutes: u64,
+    pub sources: Vec<SyncSource>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            interval_minutes: 60,
+            sources: Vec::new(),
+        }
+    }
+}
+
+// A channel or playlist that is mirrored to the listed platforms
+#[derive(Deserialize, Clone)]
+pub(crate) struct SyncSource {
+    pub url: String,
+    pub platforms: Vec<Platform>,
+    #[serde(default)]
+    pub filter: SyncFilter,
+    pub profile: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct ProfileConfig {
+    // Title rules that run after the global ones
+    pub titles: TitleConfig,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ShortsFilter {
+    #[default]
+    All,
+    Only,
+    Exclude,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct SyncFilter {
+    pub shorts: ShortsFilter,
+    // Duration bounds in seconds
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    // Case-insensitive; a video must contain one of `keywords` (if any) and none of `exclude_keywords`
+    pub keywords: Vec<String>,
+    pub exclude_keywords: Vec<String>,
+    // Upload date as YYYYMMDD, like yt-dlp reports it
+    pub published_after: Option<String>,
+}
+
+impl Config {
+    pub(crate) fn template(&self, platform: Platform) -> TemplateConfig {
+        self.templates.get(&platform).cloned().unwrap_or_else(|| TemplateConfig::default_for(platform))
+    }
+
+    pub(crate) fn profile(&self, name: &str) -> Result<&ProfileConfig> {
+        self.profiles.get(name).ok_or_else(|| anyhow!("Unknown profile: {}", name))
+    }
+
+    pub(crate) fn load(path: Option<&str>) -> Result<Config> {
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+        let content = fs::read_to_string(path)
+            .context(format!("Failed to read config file: {}", path))?;
+        serde_json::from_str(&content).context(format!("Failed to parse config file: {}", path))
+    }
+}
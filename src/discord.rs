@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use reqwest::Client;
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::progress;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
+use crate::youtube::VideoMetadata;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct DiscordConfig {
+    // From the channel's Integrations settings
+    pub webhook_url: String,
+    // Shown instead of the webhook's own name and avatar
+    pub username: Option<String>,
+    pub avatar_url: Option<String>,
+    // Post in a thread of the channel
+    pub thread_id: Option<String>,
+    // Adds a card with the title, a link to the source and the thumbnail
+    #[serde(default = "default_true")]
+    pub embed: bool,
+    // Attaches the video itself when it fits in `max_file_size`
+    #[serde(default = "default_true")]
+    pub attach_video: bool,
+    // Upload limit of the server; 10 MiB unless it is boosted
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn payload(config: &DiscordConfig, metadata: &VideoMetadata, text: &PostText) -> Value {
+    let mut payload = json!({
+        "content": text.description,
+        // Text from templates must not ping anyone
+        "allowed_mentions": { "parse": [] },
+    });
+    if let Some(username) = &config.username {
+        payload["username"] = json!(username);
+    }
+    if let Some(avatar_url) = &config.avatar_url {
+        payload["avatar_url"] = json!(avatar_url);
+    }
+    if config.embed {
+        let mut embed = json!({ "title": text.title });
+        if !metadata.webpage_url.is_empty() {
+            embed["url"] = json!(metadata.webpage_url);
+        }
+        if let Some(thumbnail) = metadata.thumbnail.as_ref().filter(|url| url.starts_with("http")) {
+            embed["image"] = json!({ "url": thumbnail });
+        }
+        payload["embeds"] = json!([embed]);
+    }
+    payload
+}
+
+// Returns the link to the message if Discord reports its server
+pub async fn upload_to_discord(config: &DiscordConfig, file_path: &str, metadata: &VideoMetadata, text: &PostText,
+                               retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+    let attach = config.attach_video && file_size <= config.max_file_size;
+    if config.attach_video && !attach {
+        println!("The video is larger than {} bytes, posting to Discord without it", config.max_file_size);
+    }
+    let payload = payload(config, metadata, text);
+    let mut query = vec![("wait", "true")];
+    if let Some(thread_id) = &config.thread_id {
+        query.push(("thread_id", thread_id.as_str()));
+    }
+
+    let pb = match attach {
+        true => progress::bytes(file_size, "Posting to Discord"),
+        false => progress::spinner("Posting to Discord"),
+    };
+    let message = retry.run("Posting the message", || async {
+        let request = client.post(&config.webhook_url).query(&query);
+        let request = if attach {
+            let file_name = Path::new(file_path).file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "video.mp4".to_string());
+            let reader = progress::open(file_path, 0, file_size, &pb).await?;
+            let video = Part::stream_with_length(progress::body(reader), file_size)
+                .file_name(file_name)
+                .mime_str("video/mp4")?;
+            request.multipart(Form::new().text("payload_json", payload.to_string()).part("files[0]", video))
+        } else {
+            request.json(&payload)
+        };
+        Ok(check_status(request.send().await?).await?.json::<Value>().await?)
+    }).await?;
+    pb.finish_with_message("Posted");
+
+    let link = match (message["guild_id"].as_str(), message["channel_id"].as_str(), message["id"].as_str()) {
+        (Some(guild), Some(channel), Some(id)) => Some(format!("https://discord.com/channels/{}/{}/{}", guild, channel, id)),
+        _ => None,
+    };
+    Ok(link)
+}
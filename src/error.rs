@@ -0,0 +1,476 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use teloxide::{ApiError, RequestError};
+use crate::jobs;
+use crate::retry::HttpError;
+use crate::ok::OkError;
+use crate::vk::VkError;
+use crate::Platform;
+
+// How much of a tool's stderr is kept to explain a failure
+const STDERR_TAIL: usize = 4096;
+
+// A tool started by `run_tool` and the job it works for
+type Tool = (Option<u64>, Arc<Mutex<Child>>);
+
+// Tools that are still running
+static TOOLS: Mutex<Vec<Tool>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Stage {
+    Download,
+    Transform,
+    Upload,
+    // Anything outside the three stages, like a broken config or store
+    Other,
+}
+
+// What yt-dlp and the Python socket and HTTP libraries under it print when the network fails
+const NETWORK_ERRORS: &[&str] = &[
+    "unable to download webpage", "unable to download api page", "urlopen error", "connection reset by peer",
+    "connection refused", "connection aborted", "remote end closed connection", "read timed out",
+    "the read operation timed out", "temporary failure in name resolution", "name or service not known",
+    "network is unreachable", "incompleteread",
+];
+
+#[derive(Debug)]
+pub(crate) enum DownloadError {
+    ToolMissing,
+    // Removed, private, age-restricted or blocked in the region; the reason as yt-dlp gives it
+    Unavailable(String),
+    RateLimited(String),
+    Network(String),
+    DiskFull,
+    Failed(String),
+}
+
+impl DownloadError {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            DownloadError::ToolMissing => "download.tool_missing",
+            DownloadError::Unavailable(_) => "download.unavailable",
+            DownloadError::RateLimited(_) => "download.rate_limited",
+            DownloadError::Network(_) => "download.network",
+            DownloadError::DiskFull => "download.disk_full",
+            DownloadError::Failed(_) => "download.failed",
+        }
+    }
+
+    // yt-dlp fails for all kinds of passing reasons, so only errors about the video itself or
+    // the machine are final
+    pub(crate) fn retryable(&self) -> bool {
+        !matches!(self, DownloadError::ToolMissing | DownloadError::Unavailable(_) | DownloadError::DiskFull)
+    }
+
+    pub(crate) fn message(&self) -> String {
+        match self {
+            DownloadError::ToolMissing => "yt-dlp is not installed or not in PATH".to_string(),
+            DownloadError::Unavailable(reason) => format!("The video is unavailable: {}", reason),
+            DownloadError::RateLimited(_) => "YouTube is rate limiting downloads, try again later".to_string(),
+            DownloadError::Network(_) => "Could not reach YouTube".to_string(),
+            DownloadError::DiskFull => "No space left on the disk to download the video".to_string(),
+            DownloadError::Failed(_) => "Failed to download the video".to_string(),
+        }
+    }
+
+    // Sorts a failed yt-dlp run by its error output
+    pub(crate) fn from_stderr(stderr: &str) -> DownloadError {
+        let line = last_error_line(stderr);
+        let lower = stderr.to_lowercase();
+        if lower.contains("no space left on device") {
+            DownloadError::DiskFull
+        } else if ["video unavailable", "private video", "sign in to confirm", "not available in your country",
+                   "has been removed", "members-only", "this live event"].iter().any(|s| lower.contains(s)) {
+            DownloadError::Unavailable(line)
+        } else if lower.contains("http error 429") || lower.contains("too many requests") {
+            DownloadError::RateLimited(line)
+        } else if NETWORK_ERRORS.iter().any(|s| lower.contains(s)) {
+            DownloadError::Network(line)
+        } else {
+            DownloadError::Failed(line)
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::RateLimited(detail) | DownloadError::Network(detail) | DownloadError::Failed(detail)
+                if !detail.is_empty() => write!(f, "{}: {}", self.message(), detail),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+impl From<io::Error> for DownloadError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => DownloadError::ToolMissing,
+            io::ErrorKind::StorageFull => DownloadError::DiskFull,
+            _ => DownloadError::Failed(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TransformError {
+    ToolMissing,
+    // The file is not a video ffmpeg can read
+    InvalidInput(String),
+    DiskFull,
+    Failed(String),
+}
+
+impl TransformError {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            TransformError::ToolMissing => "transform.tool_missing",
+            TransformError::InvalidInput(_) => "transform.invalid_input",
+            TransformError::DiskFull => "transform.disk_full",
+            TransformError::Failed(_) => "transform.failed",
+        }
+    }
+
+    pub(crate) fn retryable(&self) -> bool {
+        matches!(self, TransformError::Failed(_))
+    }
+
+    pub(crate) fn message(&self) -> String {
+        match self {
+            TransformError::ToolMissing => "ffmpeg or ffprobe is not installed or not in PATH".to_string(),
+            TransformError::InvalidInput(_) => "The downloaded file is not a readable video".to_string(),
+            TransformError::DiskFull => "No space left on the disk to transform the video".to_string(),
+            TransformError::Failed(_) => "Failed to transform the video".to_string(),
+        }
+    }
+
+    pub(crate) fn from_stderr(stderr: &str) -> TransformError {
+        let line = last_error_line(stderr);
+        let lower = stderr.to_lowercase();
+        if lower.contains("no space left on device") {
+            TransformError::DiskFull
+        } else if lower.contains("invalid data found") || lower.contains("moov atom not found") {
+            TransformError::InvalidInput(line)
+        } else {
+            TransformError::Failed(line)
+        }
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransformError::InvalidInput(detail) | TransformError::Failed(detail)
+                if !detail.is_empty() => write!(f, "{}: {}", self.message(), detail),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+impl From<io::Error> for TransformError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => TransformError::ToolMissing,
+            io::ErrorKind::StorageFull => TransformError::DiskFull,
+            _ => TransformError::Failed(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UploadErrorKind {
+    MissingCredentials,
+    // Bad or expired token, or no rights to post
+    Unauthorized,
+    FileTooLarge,
+    RateLimited,
+    Network,
+    Server,
+    // The platform refused the request for any other reason
+    Rejected,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct UploadError {
+    pub platform: Platform,
+    pub kind: UploadErrorKind,
+    pub detail: String,
+}
+
+impl UploadError {
+    pub(crate) fn missing(platform: Platform, what: &str) -> UploadError {
+        UploadError { platform, kind: UploadErrorKind::MissingCredentials, detail: format!("{} is missing", what) }
+    }
+
+    pub(crate) fn code(&self) -> &'static str {
+        match self.kind {
+            UploadErrorKind::MissingCredentials => "upload.missing_credentials",
+            UploadErrorKind::Unauthorized => "upload.unauthorized",
+            UploadErrorKind::FileTooLarge => "upload.file_too_large",
+            UploadErrorKind::RateLimited => "upload.rate_limited",
+            UploadErrorKind::Network => "upload.network",
+            UploadErrorKind::Server => "upload.server",
+            UploadErrorKind::Rejected => "upload.rejected",
+        }
+    }
+
+    pub(crate) fn retryable(&self) -> bool {
+        matches!(self.kind, UploadErrorKind::RateLimited | UploadErrorKind::Network | UploadErrorKind::Server)
+    }
+
+    pub(crate) fn message(&self) -> String {
+        let platform = self.platform;
+        match self.kind {
+            UploadErrorKind::MissingCredentials => format!("Credentials for {} are not configured", platform),
+            UploadErrorKind::Unauthorized => format!("The {} token is invalid or expired", platform),
+            UploadErrorKind::FileTooLarge => format!("The video is too large for {}", platform),
+            UploadErrorKind::RateLimited => format!("{} is rate limiting uploads, try again later", platform),
+            UploadErrorKind::Network => format!("Could not reach {}", platform),
+            UploadErrorKind::Server => format!("{} had a server error, try again later", platform),
+            UploadErrorKind::Rejected => format!("{} rejected the upload", platform),
+        }
+    }
+
+    // Sorts any error an uploader returned. Network trouble, rate limits and server errors are
+    // worth another attempt; everything the request itself got wrong is not.
+    pub(crate) fn classify(platform: Platform, error: &anyhow::Error) -> UploadError {
+        if let Some(e) = error.chain().find_map(|cause| cause.downcast_ref::<UploadError>()) {
+            return e.clone();
+        }
+        let kind = error.chain().find_map(|cause| {
+            if let Some(e) = cause.downcast_ref::<HttpError>() {
+                return Some(status_kind(e.status));
+            }
+            if let Some(e) = cause.downcast_ref::<RequestError>() {
+                return Some(match e {
+                    RequestError::RetryAfter(_) => UploadErrorKind::RateLimited,
+                    RequestError::Network(_) | RequestError::Io(_) => UploadErrorKind::Network,
+                    RequestError::InvalidJson { .. } => UploadErrorKind::Server,
+                    RequestError::Api(ApiError::InvalidToken | ApiError::BotBlocked | ApiError::BotKicked
+                                      | ApiError::NotEnoughRightsToPostMessages) => UploadErrorKind::Unauthorized,
+                    RequestError::Api(ApiError::RequestEntityTooLarge) => UploadErrorKind::FileTooLarge,
+                    RequestError::Api(ApiError::Unknown(text)) if text.contains("Internal Server Error")
+                        || text.contains("Bad Gateway") => UploadErrorKind::Server,
+                    _ => UploadErrorKind::Rejected,
+                });
+            }
+            if let Some(e) = cause.downcast_ref::<VkError>() {
+                return Some(match e.code {
+                    5 | 15 | 17 | 27 | 28 => UploadErrorKind::Unauthorized,
+                    6 | 9 => UploadErrorKind::RateLimited,
+                    1 | 10 => UploadErrorKind::Server,
+                    _ => UploadErrorKind::Rejected,
+                });
+            }
+            if let Some(e) = cause.downcast_ref::<OkError>() {
+                return Some(match e.code {
+                    10 | 102..=105 | 200..=299 => UploadErrorKind::Unauthorized,
+                    8 | 11 => UploadErrorKind::RateLimited,
+                    1 | 2 => UploadErrorKind::Server,
+                    _ => UploadErrorKind::Rejected,
+                });
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return Some(match e.status() {
+                    Some(status) => status_kind(status),
+                    None if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() => UploadErrorKind::Network,
+                    None => UploadErrorKind::Rejected,
+                });
+            }
+            None
+        });
+        UploadError { platform, kind: kind.unwrap_or(UploadErrorKind::Rejected), detail: format!("{:#}", error) }
+    }
+}
+
+fn status_kind(status: StatusCode) -> UploadErrorKind {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => UploadErrorKind::Unauthorized,
+        StatusCode::PAYLOAD_TOO_LARGE => UploadErrorKind::FileTooLarge,
+        StatusCode::TOO_MANY_REQUESTS => UploadErrorKind::RateLimited,
+        StatusCode::REQUEST_TIMEOUT => UploadErrorKind::Network,
+        status if status.is_server_error() => UploadErrorKind::Server,
+        _ => UploadErrorKind::Rejected,
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.message(), self.detail)
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+// The machine-readable summary of a failure, as stored with jobs and printed by `--json`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ErrorReport {
+    pub stage: Stage,
+    pub code: String,
+    // Short text meant for people using the bot or CLI
+    pub message: String,
+    pub retryable: bool,
+    // The full error chain
+    pub detail: String,
+}
+
+impl ErrorReport {
+    pub(crate) fn new(error: &anyhow::Error) -> ErrorReport {
+        let detail = format!("{:#}", error);
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<DownloadError>() {
+                return ErrorReport { stage: Stage::Download, code: e.code().to_string(), message: e.message(),
+                                     retryable: e.retryable(), detail };
+            }
+            if let Some(e) = cause.downcast_ref::<TransformError>() {
+                return ErrorReport { stage: Stage::Transform, code: e.code().to_string(), message: e.message(),
+                                     retryable: e.retryable(), detail };
+            }
+            if let Some(e) = cause.downcast_ref::<UploadError>() {
+                return ErrorReport { stage: Stage::Upload, code: e.code().to_string(), message: e.message(),
+                                     retryable: e.retryable(), detail };
+            }
+        }
+        ErrorReport { stage: Stage::Other, code: "other".to_string(), message: error.to_string(), retryable: false, detail }
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.message, self.code)
+    }
+}
+
+// Runs an external tool with its stderr passed through to ours as it comes, so progress stays
+// visible, while keeping the end of it to explain a failure
+pub(crate) fn run_tool(command: &mut Command) -> io::Result<(ExitStatus, String)> {
+    let mut child = command.stderr(Stdio::piped()).spawn()?;
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    supervise(child, |child| {
+        let mut tail = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = stderr.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            io::stderr().write_all(&buffer[..read]).ok();
+            tail.extend_from_slice(&buffer[..read]);
+            if tail.len() > STDERR_TAIL {
+                tail.drain(..tail.len() - STDERR_TAIL);
+            }
+        }
+        Ok((wait(child)?, String::from_utf8_lossy(&tail).to_string()))
+    })
+}
+
+// Runs an external tool for its output, like `Command::output`, but where `kill_tools` can stop it
+pub(crate) fn tool_output(command: &mut Command) -> io::Result<Output> {
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    supervise(child, |child| {
+        // Read on a thread of its own, so a full stderr pipe never stalls the tool while we read stdout
+        let errors = std::thread::spawn(move || {
+            let mut errors = Vec::new();
+            stderr.read_to_end(&mut errors).map(|_| errors)
+        });
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output)?;
+        let errors = errors.join().expect("stderr reader")?;
+        Ok(Output { status: wait(child)?, stdout: output, stderr: errors })
+    })
+}
+
+// Lists `child` among the running tools while `work` runs
+fn supervise<T>(child: Child, work: impl FnOnce(&Mutex<Child>) -> io::Result<T>) -> io::Result<T> {
+    let child = Arc::new(Mutex::new(child));
+    TOOLS.lock().expect("tool list lock").push((jobs::current(), child.clone()));
+    let result = work(&child);
+    TOOLS.lock().expect("tool list lock").retain(|(_, tool)| !Arc::ptr_eq(tool, &child));
+    result
+}
+
+// Polled rather than waited for, so `kill_tools` never waits for the lock until the tool ends
+fn wait(child: &Mutex<Child>) -> io::Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.lock().expect("tool lock").try_wait()? {
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Stops the tools that are running for job `id`; the pipeline then fails at the step that ran them
+pub(crate) fn kill_tools(id: u64) {
+    for (_, tool) in TOOLS.lock().expect("tool list lock").iter().filter(|(job, _)| *job == Some(id)) {
+        tool.lock().expect("tool lock").kill().ok();
+    }
+}
+
+// The line that explains a failure best, usually yt-dlp's "ERROR: ..." or ffmpeg's last message
+fn last_error_line(stderr: &str) -> String {
+    // ffmpeg ends its progress lines with a carriage return only
+    let lines: Vec<&str> = stderr.split(['\n', '\r']).map(str::trim).filter(|l| !l.is_empty()).collect();
+    lines.iter().rev()
+        .find(|l| l.starts_with("ERROR:"))
+        .or(lines.last())
+        .map(|l| l.trim_start_matches("ERROR:").trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(stderr: &str) -> &'static str {
+        DownloadError::from_stderr(stderr).code()
+    }
+
+    #[test]
+    fn classifies_download_errors() {
+        assert_eq!(download("ERROR: [youtube] abc: Video unavailable. This video has been removed by the uploader"),
+                   "download.unavailable");
+        assert_eq!(download("ERROR: [youtube] abc: Private video. Sign in if you've been granted access"),
+                   "download.unavailable");
+        assert_eq!(download("ERROR: [youtube] abc: Sign in to confirm your age"), "download.unavailable");
+        assert_eq!(download("ERROR: unable to download video data: HTTP Error 429: Too Many Requests"),
+                   "download.rate_limited");
+        assert_eq!(download("ERROR: [youtube] abc: Unable to download webpage: <urlopen error [Errno -3] \
+                             Temporary failure in name resolution>"), "download.network");
+        assert_eq!(download("ERROR: unable to write data: [Errno 28] No space left on device"), "download.disk_full");
+        assert_eq!(download("ERROR: unable to download video data: [Errno 104] Connection reset by peer"),
+                   "download.network");
+        assert_eq!(download("ERROR: [download] Got error: The read operation timed out"), "download.network");
+        assert_eq!(download("ERROR: Unsupported URL: https://example.com/"), "download.failed");
+        // Words that only look like network trouble
+        assert_eq!(download("ERROR: Unsupported URL: https://example.com/connection-tips"), "download.failed");
+    }
+
+    #[test]
+    fn keeps_the_last_error_line() {
+        let error = DownloadError::from_stderr("WARNING: something\nERROR: first\n[info] more\nERROR: Unsupported URL: x\n");
+        assert_eq!(error.to_string(), "Failed to download the video: Unsupported URL: x");
+        assert!(error.retryable());
+        assert!(!DownloadError::from_stderr("ERROR: Video unavailable").retryable());
+    }
+
+    #[test]
+    fn classifies_transform_errors() {
+        let code = |stderr: &str| TransformError::from_stderr(stderr).code();
+        assert_eq!(code("in.mp4: Invalid data found when processing input"), "transform.invalid_input");
+        assert_eq!(code("[mov,mp4] moov atom not found\r"), "transform.invalid_input");
+        assert_eq!(code("Error writing trailer: No space left on device"), "transform.disk_full");
+        assert_eq!(code("Conversion failed!"), "transform.failed");
+    }
+}
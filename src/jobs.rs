@@ -0,0 +1,198 @@
+use std::future::Future;
+use std::time::Duration;
+use serde_json::json;
+use crate::config::Config;
+use crate::error::{ErrorReport, UploadError};
+use crate::store::{self, Job, ApiRequest, JobStatus, RetryRecord, Store};
+use crate::upload::Published;
+use crate::webhooks::{self, Event, WebhooksConfig};
+use crate::youtube::VideoMetadata;
+use crate::Platform;
+
+tokio::task_local! {
+    // The job the running task works for, so progress bars and external tools can be told apart by job
+    static CURRENT: u64;
+}
+
+// Runs `future` as part of job `id`
+pub(crate) async fn scope<F: Future>(id: u64, future: F) -> F::Output {
+    CURRENT.scope(id, future).await
+}
+
+pub(crate) fn current() -> Option<u64> {
+    CURRENT.try_with(|id| *id).ok()
+}
+
+// Runs `f` on the blocking thread pool as part of the same job, for work that waits on yt-dlp or
+// ffmpeg. The runtime's workers stay free, and aborting the caller returns right away; the tools
+// themselves are stopped with `error::kill_tools`.
+pub(crate) async fn blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let id = current();
+    tokio::task::spawn_blocking(move || match id {
+        Some(id) => CURRENT.sync_scope(id, f),
+        None => f(),
+    }).await.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+// Keeps the job record of one pipeline run up to date and reports its events to the webhooks.
+// Failing to write the store is logged but never fails the run itself.
+#[derive(Clone)]
+pub(crate) struct JobTracker {
+    store: String,
+    webhooks: WebhooksConfig,
+    pub id: u64,
+}
+
+impl JobTracker {
+    pub(crate) fn start(config: &Config, url: &str, platforms: &[Platform]) -> JobTracker {
+        let id = Store::update(&config.store, |store| {
+            store.prune_jobs(config.job_history);
+            let id = store.next_id();
+            store.jobs.push(Job {
+                id,
+                url: url.to_string(),
+                platforms: platforms.to_vec(),
+                status: JobStatus::Queued,
+                title: None,
+                video_id: None,
+                created_at: store::now(),
+                updated_at: store::now(),
+                error: None,
+                retries: Vec::new(),
+                request: None,
+            });
+            Ok(id)
+        });
+        let id = id.unwrap_or_else(|e| {
+            eprintln!("Failed to record job for {}: {:#}", url, e);
+            0
+        });
+        let job = JobTracker { store: config.store.clone(), webhooks: config.webhooks.clone(), id };
+        job.emit(Event::Queued, json!({}));
+        job
+    }
+
+    // Tracker for a job that already exists, e.g. one whose posts were scheduled
+    pub(crate) fn resume(config: &Config, id: u64) -> JobTracker {
+        JobTracker { store: config.store.clone(), webhooks: config.webhooks.clone(), id }
+    }
+
+    fn emit(&self, event: Event, data: serde_json::Value) {
+        webhooks::emit(&self.webhooks, &self.store, self.id, event, data);
+    }
+
+    fn change(&self, change: impl FnOnce(&mut Job)) {
+        let result = Store::update(&self.store, |store| {
+            if let Some(job) = store.jobs.iter_mut().find(|j| j.id == self.id) {
+                change(job);
+                job.updated_at = store::now();
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to update job #{}: {:#}", self.id, e);
+        }
+    }
+
+    // A cancelled job stays cancelled while the pipeline runs on to the point where it stops
+    pub(crate) fn set_status(&self, status: JobStatus) {
+        self.change(|job| if job.status != JobStatus::Cancelled {
+            job.status = status;
+        });
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.change(|job| job.status = JobStatus::Cancelled);
+    }
+
+    // Records how a job queued through the API should run
+    pub(crate) fn set_request(&self, request: ApiRequest) {
+        self.change(|job| job.request = Some(request));
+    }
+
+    pub(crate) fn set_title(&self, title: &str) {
+        self.change(|job| job.title = Some(title.to_string()));
+    }
+
+    pub(crate) fn retry(&self, platform: Platform, attempt: u32, error: &anyhow::Error, delay: Duration) {
+        self.change(|job| job.retries.push(RetryRecord {
+            platform,
+            attempt,
+            code: UploadError::classify(platform, error).code().to_string(),
+            error: format!("{:#}", error),
+            delay_secs: delay.as_secs_f64(),
+        }));
+    }
+
+    // The source video is on disk at `metadata.filename`
+    pub(crate) fn downloaded(&self, metadata: &VideoMetadata) {
+        self.change(|job| job.video_id = Some(metadata.id.clone()));
+        self.emit(Event::Downloaded, json!({ "video": metadata, "file": webhooks::file_info(&metadata.filename) }));
+    }
+
+    pub(crate) fn transformed(&self, metadata: &VideoMetadata, file: &str) {
+        self.emit(Event::Transformed, json!({ "video": metadata, "file": webhooks::file_info(file) }));
+    }
+
+    pub(crate) fn uploaded(&self, metadata: &VideoMetadata, file: &str, published: &Published) {
+        self.emit(Event::Uploaded, json!({
+            "video": metadata,
+            "file": webhooks::file_info(file),
+            "upload": { "platform": published.platform, "url": published.url },
+        }));
+    }
+
+    // Puts a failed or cancelled job back in the queue; its earlier retries stay on record
+    pub(crate) fn requeue(&self) {
+        self.change(|job| {
+            job.status = JobStatus::Queued;
+            job.error = None;
+        });
+        self.emit(Event::Queued, json!({}));
+    }
+
+    pub(crate) fn finish(&self, result: &anyhow::Result<JobStatus>) {
+        let mut cancelled = false;
+        self.change(|job| match result {
+            _ if job.status == JobStatus::Cancelled => cancelled = true,
+            Ok(status) => {
+                job.status = *status;
+                job.error = None;
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(ErrorReport::new(e));
+            }
+        });
+        if let (false, Err(e)) = (cancelled, result) {
+            self.emit(Event::Failed, json!({ "error": ErrorReport::new(e) }));
+        }
+    }
+}
+
+// Most recent jobs first
+pub(crate) fn recent(config: &Config, limit: usize) -> anyhow::Result<Vec<Job>> {
+    let mut jobs = Store::load(&config.store)?.jobs;
+    jobs.reverse();
+    jobs.truncate(limit);
+    Ok(jobs)
+}
+
+pub(crate) fn describe(job: &Job) -> String {
+    let mut line = format!("#{} {} {}", job.id, job.status, job.title.as_deref().unwrap_or(&job.url));
+    if !job.retries.is_empty() {
+        line.push_str(&format!(" ({} retries)", job.retries.len()));
+    }
+    if let Some(error) = &job.error {
+        line.push_str(&format!(": {}", error));
+    }
+    for retry in &job.retries {
+        line.push_str(&format!("\n  {} attempt {} failed with {}, retried after {:.0}s: {}",
+                               retry.platform, retry.attempt, retry.code, retry.delay_secs, retry.error));
+    }
+    line
+}
@@ -1,8 +1,19 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use std::fmt;
+use std::sync::Arc;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 use youtube::download_video;
 use transform::transform_video;
 use anyhow::{Result};
+use crate::config::Config;
+use crate::error::ErrorReport;
 use crate::transform::EncodingPasses;
+use crate::process::ProcessOptions;
+use crate::retry::Retry;
+use crate::schedule::PublishAt;
+use crate::template::Markup;
+use crate::upload::{Post, UploadOptions};
+use crate::youtube::VideoMetadata;
 
 mod youtube;
 mod rutube;
@@ -12,20 +23,113 @@ mod transform;
 mod bot;
 mod upload;
 mod process;
+mod config;
+mod store;
+mod sync;
+mod template;
+mod titles;
+mod schedule;
+mod retry;
+mod jobs;
+mod error;
+mod progress;
+mod announce;
+mod peertube;
+mod resumable;
+mod ok;
+mod s3;
+mod archive;
+mod youtube_api;
+mod discord;
+mod mastodon;
+mod matrix;
+mod webhooks;
+mod server;
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Platform {
     Rutube,
     Telegram,
     Vk,
+    Peertube,
+    Ok,
+    S3,
+    Folder,
+    Webdav,
+    Youtube,
+    Discord,
+    Mastodon,
+    Matrix,
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Platform::Rutube => "rutube",
+            Platform::Telegram => "telegram",
+            Platform::Vk => "vk",
+            Platform::Peertube => "peertube",
+            Platform::Ok => "ok",
+            Platform::S3 => "s3",
+            Platform::Folder => "folder",
+            Platform::Webdav => "webdav",
+            Platform::Youtube => "youtube",
+            Platform::Discord => "discord",
+            Platform::Mastodon => "mastodon",
+            Platform::Matrix => "matrix",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Platform {
+    // Posts about the video rather than copies of it; they go out after the other platforms so
+    // their texts can link to them
+    pub(crate) fn is_notifier(self) -> bool {
+        matches!(self, Platform::Discord | Platform::Mastodon | Platform::Matrix)
+    }
+
+    // Publishes at a set time by itself, so a scheduled video is uploaded right away rather than
+    // held back until then
+    pub(crate) fn schedules_itself(self) -> bool {
+        matches!(self, Platform::Youtube)
+    }
 }
 
 #[derive(Parser)]
 #[command(name = "youtube-to-platforms")]
-#[command(about = "CLI tool to download YouTube videos and upload them to Rutube, Telegram, and VK")]
+#[command(about = "CLI tool to download YouTube videos and upload them to Rutube, Telegram, VK, PeerTube, OK, S3, a folder, WebDAV or YouTube and announce them on Discord, Mastodon, and Matrix")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Print failures (and the output of `jobs`) as JSON
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+// Credentials and Telegram Bot API settings of the commands that upload
+#[derive(Args)]
+pub(crate) struct UploadArgs {
+    #[arg(short, long)]
+    pub rutube_api_key: Option<String>,
+    #[arg(long, default_value = "https://api.telegram.org/")]
+    pub bot_api_url: String,
+    /// Largest file sent in one piece; 50 MB for the cloud Bot API and 2 GB for a local server
+    #[arg(long)]
+    pub max_file_size: Option<u64>,
+    /// `--bot-api-url` points to a local `telegram-bot-api --local` server
+    #[arg(long)]
+    pub local_bot_api: bool,
+    /// The local Bot API server shares the filesystem, so videos are passed by path
+    #[arg(long, requires = "local_bot_api")]
+    pub bot_api_shares_files: bool,
+    #[arg(short, long)]
+    pub bot_token: Option<String>,
+    #[arg(short, long)]
+    pub chat_id: Option<i64>,
+    #[arg(short, long)]
+    pub vk_access_token: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -47,66 +151,173 @@ enum Commands {
         file: String,
         #[arg(short, long)]
         title: String,
-        #[arg(short, long)]
-        rutube_api_key: Option<String>,
-        #[arg(long, default_value = "https://api.telegram.org/")]
-        bot_api_url: String,
-        #[arg(long, default_value = "50000000")]
-        max_file_size: u64,
-        #[arg(short, long)]
-        bot_token: Option<String>,
-        #[arg(short, long)]
-        chat_id: Option<i64>,
-        #[arg(short, long)]
-        vk_access_token: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        #[arg(long, value_delimiter = ',')]
+        tags: Vec<String>,
+        #[command(flatten)]
+        upload: UploadArgs,
+        /// Config with the settings of platforms that have no command-line options
+        #[arg(long)]
+        config: Option<String>,
     },
     Process {
         #[arg(short, long)]
         url: String,
-        #[arg(short, long)]
-        platform: Platform,
+        #[arg(short, long, value_delimiter = ',')]
+        platform: Vec<Platform>,
         #[arg(short, long, default_value = "./videos")]
         output: String,
         #[arg(long)]
         delete_youtube: bool,
         #[arg(long)]
         delete_transformed: bool,
-        #[arg(short, long)]
-        rutube_api_key: Option<String>,
-        #[arg(long, default_value = "https://api.telegram.org/")]
-        bot_api_url: String,
-        #[arg(long, default_value = "50000000")]
-        max_file_size: u64,
-        #[arg(short, long)]
-        bot_token: Option<String>,
-        #[arg(short, long)]
-        chat_id: Option<i64>,
-        #[arg(short, long)]
-        vk_access_token: Option<String>,
+        #[command(flatten)]
+        upload: UploadArgs,
+        /// Playlist items to process, e.g. `1-10` or `1,4,7-`
+        #[arg(long)]
+        items: Option<String>,
+        /// Process playlist items from oldest to newest
+        #[arg(long)]
+        reverse: bool,
+        /// Title pattern for playlist items, e.g. "{title} (Episode {n}/{total})"
+        #[arg(long)]
+        title_format: Option<String>,
+        #[arg(long)]
+        config: Option<String>,
+        /// Profile from the config whose settings apply to this run
+        #[arg(long)]
+        profile: Option<String>,
+        /// Publish at the given time ("YYYY-MM-DD HH:MM" in the configured time zone, or RFC 3339)
+        #[arg(long)]
+        publish_at: Option<String>,
+        /// Publish in the next free posting slot of each platform
+        #[arg(long, conflicts_with = "publish_at")]
+        schedule: bool,
     },
+    // The bot needs its token, which the other commands only need for Telegram uploads
+    #[command(mut_arg("bot_token", |arg| arg.required(true)))]
     Bot {
-        #[arg(short, long)]
-        bot_token: String,
-        #[arg(short, long)]
-        platform: Platform,
+        #[arg(short, long, value_delimiter = ',')]
+        platform: Vec<Platform>,
         #[arg(short, long, default_value = "./videos")]
         output: String,
         #[arg(long)]
         delete_youtube: bool,
         #[arg(long)]
         delete_transformed: bool,
-        #[arg(short, long)]
-        rutube_api_key: Option<String>,
-        #[arg(long, default_value = "https://api.telegram.org/")]
-        bot_api_url: String,
-        #[arg(long, default_value = "50000000")]
-        max_file_size: u64,
-        #[arg(short, long)]
-        chat_id: Option<i64>,
-        #[arg(short, long)]
-        vk_access_token: Option<String>,
+        #[command(flatten)]
+        upload: UploadArgs,
         #[arg(short, long, value_delimiter = ',')]
         allowed_users: Vec<u64>,
+        #[arg(long)]
+        config: Option<String>,
+        #[arg(long)]
+        profile: Option<String>,
+        /// Publish videos in the next free posting slot instead of right away
+        #[arg(long)]
+        schedule: bool,
+    },
+    /// Publish new videos of the channels and playlists listed in the config
+    Sync {
+        #[arg(long)]
+        config: String,
+        #[arg(short, long, default_value = "./videos")]
+        output: String,
+        #[arg(long)]
+        delete_youtube: bool,
+        #[arg(long)]
+        delete_transformed: bool,
+        #[command(flatten)]
+        upload: UploadArgs,
+        /// Check once and exit instead of checking periodically
+        #[arg(long)]
+        once: bool,
+        /// Record the current videos as published without uploading them
+        #[arg(long)]
+        seed: bool,
+    },
+    /// Serve the web dashboard and an HTTP API for submitting and following jobs
+    Serve {
+        #[arg(long)]
+        config: String,
+        /// Address to listen on instead of `server.listen` from the config
+        #[arg(long)]
+        listen: Option<String>,
+        /// Platforms of jobs that do not list their own
+        #[arg(short, long, value_delimiter = ',')]
+        platform: Vec<Platform>,
+        #[arg(short, long, default_value = "./videos")]
+        output: String,
+        #[arg(long)]
+        delete_youtube: bool,
+        #[arg(long)]
+        delete_transformed: bool,
+        #[command(flatten)]
+        upload: UploadArgs,
+        #[arg(long)]
+        profile: Option<String>,
+    },
+    /// Work with the title rewrite rules
+    Titles {
+        #[command(subcommand)]
+        command: TitlesCommand,
+    },
+    /// Work with scheduled posts
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommand,
+    },
+    /// Show recent jobs with their status and retries
+    Jobs {
+        #[arg(long)]
+        config: Option<String>,
+        #[arg(long, default_value = "20")]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum ScheduleCommand {
+    /// Release scheduled posts when they are due
+    Run {
+        #[arg(long)]
+        config: String,
+        #[command(flatten)]
+        upload: UploadArgs,
+    },
+    /// List scheduled posts
+    List {
+        #[arg(long)]
+        config: String,
+    },
+    /// Remove a scheduled post
+    Remove {
+        #[arg(long)]
+        config: String,
+        id: u64,
+    },
+    /// Move a post to another position in the queue of its platform
+    Move {
+        #[arg(long)]
+        config: String,
+        id: u64,
+        position: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum TitlesCommand {
+    /// Show how the title of a video would be rewritten for each platform
+    Test {
+        #[arg(short, long)]
+        url: String,
+        #[arg(long)]
+        config: Option<String>,
+        #[arg(long)]
+        profile: Option<String>,
+        #[arg(short, long, value_delimiter = ',')]
+        platform: Vec<Platform>,
     },
 }
 
@@ -116,8 +327,19 @@ impl Cli {
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let json = cli.json;
+
+    let result = run(cli.command, json).await;
+    webhooks::flush().await;
+    if let (true, Err(e)) = (json, &result) {
+        println!("{}", serde_json::json!({ "error": ErrorReport::new(e) }));
+        std::process::exit(1);
+    }
+    result
+}
 
-    match cli.command {
+async fn run(command: Commands, json: bool) -> Result<()> {
+    match command {
         Commands::Download { url, output } => {
             println!("Downloading from: {}", url);
             println!("Saving to: {}", output);
@@ -131,15 +353,25 @@ async fn main() -> Result<()> {
             platform,
             file,
             title,
-            rutube_api_key,
-            bot_api_url,
-            max_file_size,
-            bot_token,
-            chat_id,
-            vk_access_token,
+            description,
+            tags,
+            upload,
+            config,
         } => {
-            upload::upload(platform, &file, &title, rutube_api_key, &bot_api_url, max_file_size,
-                           bot_token, chat_id, vk_access_token, "", "").await?;
+            let config = Config::load(config.as_deref())?;
+            let options = UploadOptions::new(&upload, &config);
+            let metadata = VideoMetadata {
+                title,
+                description: description.unwrap_or_default(),
+                tags,
+                ..Default::default()
+            };
+            let text = config.template(platform)
+                .render(platform, &template::variables(&metadata, ""), Markup::Plain)?;
+            let retry = Retry { platform, policy: config.retry.policy(platform), job: None };
+            telegram::check_server(&options).await?;
+            let post = Post { text, markup: Markup::Plain, telegram: Vec::new(), publish_at: None };
+            upload::upload(platform, &file, &metadata, &post, &options, &retry).await?;
         }
         Commands::Process {
             url,
@@ -147,43 +379,183 @@ async fn main() -> Result<()> {
             output,
             delete_youtube,
             delete_transformed,
-            rutube_api_key,
-            bot_api_url,
-            max_file_size,
-            bot_token,
-            chat_id,
-            vk_access_token,
+            upload,
+            items,
+            reverse,
+            title_format,
+            config,
+            profile,
+            publish_at,
+            schedule,
         } => {
-            process::youtube(&url, platform, &output, delete_youtube, delete_transformed,
-                             rutube_api_key, &bot_api_url,
-                             max_file_size, bot_token, chat_id, vk_access_token).await?;
+            let config = Config::load(config.as_deref())?;
+            let publish_at = match publish_at {
+                Some(time) => Some(PublishAt::Time(config.schedule.parse_time(&time)?)),
+                None if schedule => Some(PublishAt::NextSlot),
+                None => None,
+            };
+            let options = ProcessOptions {
+                platforms: platform,
+                output,
+                delete_youtube,
+                delete_transformed,
+                upload: UploadOptions::new(&upload, &config),
+                config: Arc::new(config),
+                profile,
+                publish_at,
+            };
+            telegram::check_server(&options.upload).await?;
+            if youtube::is_playlist_url(&url) {
+                let batch_options = process::BatchOptions { items, reverse, title_format };
+                process::batch(&url, &options, &batch_options).await?;
+            } else {
+                process::youtube(&url, &options).await?;
+            }
         }
         Commands::Bot {
-            bot_token,
             platform,
             output,
             delete_youtube,
             delete_transformed,
-            rutube_api_key,
-            bot_api_url,
-            max_file_size,
-            chat_id,
-            vk_access_token,
+            upload,
             allowed_users,
+            config,
+            profile,
+            schedule,
         } => {
             println!("Telegram bot...");
 
-            bot::run(&bot_token,
-                     platform,
-                     &output,
-                     delete_youtube,
-                     delete_transformed,
-                     rutube_api_key,
-                     &bot_api_url,
-                     max_file_size,
-                     chat_id,
-                     vk_access_token,
-                     allowed_users).await?;
+            let config = Config::load(config.as_deref())?;
+            let options = ProcessOptions {
+                platforms: platform,
+                output,
+                delete_youtube,
+                delete_transformed,
+                upload: UploadOptions::new(&upload, &config),
+                config: Arc::new(config),
+                profile,
+                publish_at: schedule.then_some(PublishAt::NextSlot),
+            };
+            bot::run(options, allowed_users).await?;
+        }
+        Commands::Sync {
+            config,
+            output,
+            delete_youtube,
+            delete_transformed,
+            upload,
+            once,
+            seed,
+        } => {
+            // Sync takes its platforms from each configured source
+            let config = Config::load(Some(&config))?;
+            let options = ProcessOptions {
+                platforms: Vec::new(),
+                output,
+                delete_youtube,
+                delete_transformed,
+                upload: UploadOptions::new(&upload, &config),
+                config: Arc::new(config),
+                profile: None,
+                publish_at: None,
+            };
+            telegram::check_server(&options.upload).await?;
+            if once || seed {
+                let count = sync::run_once(&options, seed).await?;
+                println!("Sync finished, {} new video(s) published", count);
+            } else {
+                sync::run_forever(options).await;
+            }
+        }
+        Commands::Serve {
+            config,
+            listen,
+            platform,
+            output,
+            delete_youtube,
+            delete_transformed,
+            upload,
+            profile,
+        } => {
+            let config = Config::load(Some(&config))?;
+            let options = ProcessOptions {
+                platforms: platform,
+                output,
+                delete_youtube,
+                delete_transformed,
+                upload: UploadOptions::new(&upload, &config),
+                config: Arc::new(config),
+                profile,
+                publish_at: None,
+            };
+            telegram::check_server(&options.upload).await?;
+            server::run(options, listen).await?;
+        }
+        Commands::Schedule { command } => match command {
+            ScheduleCommand::Run {
+                config,
+                upload,
+            } => {
+                // Posts carry their own files and platforms
+                let config = Config::load(Some(&config))?;
+                let options = ProcessOptions {
+                    platforms: Vec::new(),
+                    output: String::new(),
+                    delete_youtube: false,
+                    delete_transformed: false,
+                    upload: UploadOptions::new(&upload, &config),
+                    config: Arc::new(config),
+                    profile: None,
+                    publish_at: None,
+                };
+                telegram::check_server(&options.upload).await?;
+                schedule::run(options).await;
+            }
+            ScheduleCommand::List { config } => {
+                let config = Config::load(Some(&config))?;
+                for post in schedule::list(&config)? {
+                    println!("{}", schedule::describe(&config, &post));
+                }
+            }
+            ScheduleCommand::Remove { config, id } => {
+                let post = schedule::unschedule(&Config::load(Some(&config))?, id)?;
+                println!("Removed #{} {}", post.id, post.metadata.title);
+            }
+            ScheduleCommand::Move { config, id, position } => {
+                let config = Config::load(Some(&config))?;
+                schedule::reorder(&config, id, position)?;
+                for post in schedule::list(&config)? {
+                    println!("{}", schedule::describe(&config, &post));
+                }
+            }
+        },
+        Commands::Jobs { config, limit } => {
+            let config = Config::load(config.as_deref())?;
+            let jobs = jobs::recent(&config, limit)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&jobs)?);
+            } else {
+                for job in &jobs {
+                    println!("{}", jobs::describe(job));
+                }
+            }
+        }
+        Commands::Titles { command: TitlesCommand::Test { url, config, profile, platform } } => {
+            let config = Config::load(config.as_deref())?;
+            let metadata = youtube::get_video_metadata(&url, ".")?;
+            let platforms = if platform.is_empty() {
+                Platform::value_variants().to_vec()
+            } else {
+                platform
+            };
+            println!("Original: {}", metadata.title);
+            for platform in platforms {
+                let rewritten = titles::rewrite(&config, profile.as_deref(), platform, &metadata)?;
+                println!("{}: {}", platform, rewritten.title);
+                if !rewritten.tags.is_empty() {
+                    println!("{}  tags: {}", " ".repeat(platform.to_string().len()), rewritten.tags.join(", "));
+                }
+            }
         }
     }
 
@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Client, StatusCode};
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::progress;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
+use crate::youtube::VideoMetadata;
+
+// How long to wait for the instance to process an uploaded video
+const PROCESSING_ATTEMPTS: u32 = 60;
+const PROCESSING_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct MastodonConfig {
+    // Instance URL, e.g. `https://mastodon.social`
+    pub base_url: String,
+    // Token of an application with the `write:statuses` and `write:media` scopes
+    pub access_token: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    // Language code like `en` or `ru`
+    pub language: Option<String>,
+    // Larger videos are replaced by their thumbnail; 40 MB on most instances
+    #[serde(default = "default_max_media_size")]
+    pub max_media_size: u64,
+}
+
+fn default_max_media_size() -> u64 {
+    40_000_000
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+    Direct,
+}
+
+impl Visibility {
+    fn name(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+            Visibility::Direct => "direct",
+        }
+    }
+}
+
+impl MastodonConfig {
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+}
+
+// Uploads the video, or the thumbnail if the video is too large, and returns the attachment ID
+// once the instance has processed it
+async fn upload_media(client: &Client, config: &MastodonConfig, file_path: &str, metadata: &VideoMetadata,
+                      text: &PostText, retry: &Retry<'_>) -> Result<Option<String>> {
+    let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+    let path = match &metadata.thumbnail_file {
+        _ if file_size <= config.max_media_size => file_path,
+        Some(thumbnail) => {
+            println!("The video is larger than {} bytes, attaching the thumbnail to the Mastodon post", config.max_media_size);
+            thumbnail
+        }
+        None => return Ok(None),
+    };
+    let size = fs::metadata(path).map(|m| m.len()).context(format!("Failed to get metadata of {}", path))?;
+    let file_name = Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video.mp4".to_string());
+    let mime = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "video/mp4",
+    };
+
+    let pb = progress::bytes(size, "Uploading to Mastodon");
+    let media = retry.run("Uploading the media", || async {
+        let reader = progress::open(path, 0, size, &pb).await?;
+        let file = Part::stream_with_length(progress::body(reader), size)
+            .file_name(file_name.clone())
+            .mime_str(mime)?;
+        let res = client.post(config.url("api/v2/media"))
+            .bearer_auth(&config.access_token)
+            .multipart(Form::new().part("file", file).text("description", text.title.clone()))
+            .send()
+            .await?;
+        Ok(check_status(res).await?.json::<Value>().await?)
+    }).await?;
+    pb.finish_with_message("Upload complete");
+    let id = media["id"].as_str().ok_or_else(|| anyhow!("No media ID in {}", media))?;
+
+    // A large file is processed after the upload; the post can only refer to it once that is done
+    let mut ready = !media["url"].is_null();
+    for _ in 0..PROCESSING_ATTEMPTS {
+        if ready {
+            break;
+        }
+        tokio::time::sleep(PROCESSING_INTERVAL).await;
+        ready = retry.run("Checking the media", || async {
+            let res = client.get(config.url(&format!("api/v1/media/{}", id)))
+                .bearer_auth(&config.access_token)
+                .send()
+                .await?;
+            // 206 means it is still being processed
+            Ok(check_status(res).await?.status() == StatusCode::OK)
+        }).await?;
+    }
+    if !ready {
+        return Err(anyhow!("Mastodon did not finish processing the media"));
+    }
+    Ok(Some(id.to_string()))
+}
+
+// Returns the link to the status
+pub async fn upload_to_mastodon(config: &MastodonConfig, file_path: &str, metadata: &VideoMetadata, text: &PostText,
+                                retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    let media = upload_media(&client, config, file_path, metadata, text, retry).await?;
+
+    let mut status = json!({
+        "status": text.description,
+        "visibility": config.visibility.name(),
+        "media_ids": media.into_iter().collect::<Vec<_>>(),
+    });
+    if let Some(language) = &config.language {
+        status["language"] = json!(language);
+    }
+    // The same key for every attempt, so a retry after a lost response posts once
+    let idempotency_key = format!("video-publisher-{}-{}", metadata.id, chrono::Utc::now().timestamp_millis());
+    let posted = retry.run("Posting the status", || async {
+        let res = client.post(config.url("api/v1/statuses"))
+            .bearer_auth(&config.access_token)
+            .header("Idempotency-Key", &idempotency_key)
+            .json(&status)
+            .send()
+            .await?;
+        Ok(check_status(res).await?.json::<Value>().await?)
+    }).await?;
+    Ok(posted["url"].as_str().map(str::to_string))
+}
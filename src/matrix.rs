@@ -0,0 +1,47 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::retry::{check_status, Retry};
+use crate::template::{Markup, PostText};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct MatrixConfig {
+    // Client-server API base, e.g. `https://matrix.example.org`
+    pub homeserver: String,
+    // Token of the account that posts; it has to be a member of the room
+    pub access_token: String,
+    // Internal ID like `!abc:example.org`, not an alias
+    pub room_id: String,
+    // `html` sends the template's markup as the formatted body, with the plain text as fallback
+    #[serde(default)]
+    pub parse_mode: Markup,
+}
+
+// Returns the matrix.to link to the message
+pub async fn upload_to_matrix(config: &MatrixConfig, video_id: &str, text: &PostText, markup: Markup,
+                              retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    // The same transaction ID for every attempt, so a retry after a lost response posts once
+    let transaction = format!("video-publisher-{}-{}", video_id, chrono::Utc::now().timestamp_millis());
+    let mut url = Url::parse(&config.homeserver).context(format!("Invalid Matrix homeserver: {}", config.homeserver))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid Matrix homeserver: {}", config.homeserver))?
+        .pop_if_empty()
+        .extend(["_matrix", "client", "v3", "rooms", &config.room_id, "send", "m.room.message", &transaction]);
+
+    let mut content = json!({ "msgtype": "m.text", "body": markup.strip(&text.description) });
+    if markup == Markup::Html {
+        content["format"] = json!("org.matrix.custom.html");
+        content["formatted_body"] = json!(text.description.replace('\n', "<br>"));
+    }
+    let sent = retry.run("Sending the message", || async {
+        let res = client.put(url.clone())
+            .bearer_auth(&config.access_token)
+            .json(&content)
+            .send()
+            .await?;
+        Ok(check_status(res).await?.json::<Value>().await?)
+    }).await?;
+    Ok(sent["event_id"].as_str().map(|event| format!("https://matrix.to/#/{}/{}", config.room_id, event)))
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use md5::{Digest, Md5};
+use reqwest::{Client, multipart::{Form, Part}};
+use serde::Deserialize;
+use serde_json::Value;
+use crate::progress;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
+use crate::youtube::VideoMetadata;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct OkConfig {
+    pub application_key: String,
+    // Signs every call when set; OK requires it unless the app allows unsigned calls
+    pub application_secret_key: Option<String>,
+    pub access_token: String,
+    // Group to publish to instead of the user's own videos
+    pub group_id: Option<u64>,
+    pub privacy: Option<Privacy>,
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+}
+
+fn default_api_url() -> String {
+    "https://api.ok.ru/fb.do".to_string()
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Privacy {
+    Public,
+    Friends,
+    Private,
+}
+
+impl Privacy {
+    fn name(self) -> &'static str {
+        match self {
+            Privacy::Public => "PUBLIC",
+            Privacy::Friends => "FRIENDS",
+            Privacy::Private => "PRIVATE",
+        }
+    }
+}
+
+// An error reported in the body of an OK API response
+#[derive(Debug)]
+pub(crate) struct OkError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for OkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OK API error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for OkError {}
+
+fn check_api_error(res: &Value) -> Result<()> {
+    match res.get("error_code") {
+        Some(code) => Err(OkError {
+            code: code.as_i64().unwrap_or(0),
+            message: res["error_msg"].as_str().unwrap_or_default().to_string(),
+        }.into()),
+        None => Ok(()),
+    }
+}
+
+// md5 over the sorted parameters followed by md5(access_token + application secret key)
+fn signature(params: &BTreeMap<&str, String>, access_token: &str, secret: &str) -> String {
+    let secret = hex::encode(Md5::digest(format!("{}{}", access_token, secret)));
+    let payload: String = params.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    hex::encode(Md5::digest(format!("{}{}", payload, secret)))
+}
+
+async fn call(client: &Client, config: &OkConfig, method: &str, params: &[(&str, String)],
+              retry: &Retry<'_>) -> Result<Value> {
+    let mut params: BTreeMap<&str, String> = params.iter().cloned().collect();
+    params.insert("application_key", config.application_key.clone());
+    params.insert("method", method.to_string());
+    params.insert("format", "json".to_string());
+    if let Some(secret) = &config.application_secret_key {
+        params.insert("sig", signature(&params, &config.access_token, secret));
+    }
+    params.insert("access_token", config.access_token.clone());
+
+    retry.run(&format!("Calling {}", method), || async {
+        let res = client.post(&config.api_url).form(&params).send().await?;
+        let res = check_status(res).await?.json::<Value>().await?;
+        check_api_error(&res)?;
+        Ok(res)
+    }).await
+}
+
+// Returns the link to the uploaded video
+pub async fn upload_to_ok(config: &OkConfig, file_path: &str, metadata: &VideoMetadata, text: &PostText,
+                          retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+    let file_name = Path::new(file_path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video.mp4".to_string());
+
+    // Step 1: Get upload URL
+    let mut params = vec![("file_name", file_name.clone()), ("file_size", file_size.to_string())];
+    if let Some(group_id) = config.group_id {
+        params.push(("gid", group_id.to_string()));
+    }
+    let res = call(&client, config, "video.getUploadUrl", &params, retry).await?;
+    let upload_url = res["upload_url"].as_str().ok_or_else(|| anyhow!("Failed to get upload URL"))?;
+    let video_id = res["video_id"].as_i64()
+        .map(|id| id.to_string())
+        .or(res["video_id"].as_str().map(str::to_string))
+        .ok_or_else(|| anyhow!("Failed to get video ID"))?;
+
+    // Step 2: Upload video
+    let pb = progress::bytes(file_size, "Uploading to OK");
+    let body = retry.run("Uploading the video", || async {
+        let reader = progress::open(file_path, 0, file_size, &pb).await?;
+        let video = Part::stream_with_length(progress::body(reader), file_size)
+            .file_name(file_name.clone())
+            .mime_str("video/mp4")?;
+        let res = client.post(upload_url)
+            .multipart(Form::new().part("data", video))
+            .send()
+            .await?;
+        Ok(check_status(res).await?.text().await?)
+    }).await?;
+    pb.finish_with_message("Upload complete");
+    println!("OK Upload Response: {}", body);
+
+    // Step 3: Set the details
+    let mut params = vec![
+        ("vid", video_id.clone()),
+        ("title", text.title.clone()),
+        ("description", text.description.clone()),
+    ];
+    if !metadata.tags.is_empty() {
+        params.push(("tags", metadata.tags.join(",")));
+    }
+    if let Some(privacy) = config.privacy {
+        params.push(("privacy", privacy.name().to_string()));
+    }
+    call(&client, config, "video.update", &params, retry).await?;
+
+    Ok(Some(format!("https://ok.ru/video/{}", video_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_sorted_parameters() {
+        // md5("tkn123SECRETKEY") = d2cf9273b36dc14b6b106911d222ecac, then
+        // md5("application_key=CBAABCDEFformat=jsonmethod=users.getCurrentUser" + that)
+        let params = BTreeMap::from([
+            ("method", "users.getCurrentUser".to_string()),
+            ("format", "json".to_string()),
+            ("application_key", "CBAABCDEF".to_string()),
+        ]);
+        assert_eq!(signature(&params, "tkn123", "SECRETKEY"), "921132b37b492fe82560f849609c91f4");
+    }
+}
@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Client, RequestBuilder, Url};
+use reqwest::multipart::Form;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::resumable::Resumable;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
+use crate::youtube::VideoMetadata;
+
+// Size of the parts the file is sent in; a broken connection costs at most one of them
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+// PeerTube takes at most 5 tags of 2 to 30 characters
+const MAX_TAGS: usize = 5;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct PeertubeConfig {
+    // Instance URL, e.g. `https://peertube.example.org`
+    pub base_url: String,
+    pub username: String,
+    pub password: String,
+    pub channel_id: u64,
+    #[serde(default)]
+    pub privacy: Privacy,
+    // IDs from `/api/v1/videos/categories` and `/api/v1/videos/licences`
+    pub category: Option<u32>,
+    pub licence: Option<u32>,
+    // Language code like `en` or `ru`
+    pub language: Option<String>,
+    // Keep the video hidden until the instance has transcoded it
+    #[serde(default = "default_true")]
+    pub wait_transcoding: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Privacy {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+    Internal,
+}
+
+impl Privacy {
+    fn id(self) -> u8 {
+        match self {
+            Privacy::Public => 1,
+            Privacy::Unlisted => 2,
+            Privacy::Private => 3,
+            Privacy::Internal => 4,
+        }
+    }
+}
+
+struct Session<'a> {
+    client: Client,
+    base_url: Url,
+    token: String,
+    retry: &'a Retry<'a>,
+}
+
+impl Session<'_> {
+    fn url(&self, path: &str) -> Result<Url> {
+        self.base_url.join(path).context(format!("Invalid PeerTube URL: {}", path))
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        request.bearer_auth(&self.token)
+    }
+}
+
+// Trades the account password for an access token, using the instance's own OAuth client
+async fn login(client: &Client, base_url: &Url, config: &PeertubeConfig, retry: &Retry<'_>) -> Result<String> {
+    let oauth_client = retry.run("Requesting the OAuth client", || async {
+        let res = client.get(base_url.join("api/v1/oauth-clients/local")?).send().await?;
+        Ok(check_status(res).await?.json::<Value>().await?)
+    }).await?;
+    let client_id = oauth_client["client_id"].as_str().ok_or_else(|| anyhow!("No client_id in {}", oauth_client))?;
+    let client_secret = oauth_client["client_secret"].as_str().ok_or_else(|| anyhow!("No client_secret in {}", oauth_client))?;
+
+    let token = retry.run("Requesting an access token", || async {
+        let res = client.post(base_url.join("api/v1/users/token")?)
+            .form(&[
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("grant_type", "password"),
+                ("response_type", "code"),
+                ("username", config.username.as_str()),
+                ("password", config.password.as_str()),
+            ])
+            .send()
+            .await?;
+        Ok(check_status(res).await?.json::<Value>().await?)
+    }).await?;
+    token["access_token"].as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("No access_token in the PeerTube token response"))
+}
+
+// Creates the video with its details and returns the URL its bytes are sent to
+async fn start_upload(session: &Session<'_>, config: &PeertubeConfig, file_path: &str, file_size: u64,
+                      metadata: &VideoMetadata, text: &PostText) -> Result<String> {
+    let file_name = Path::new(file_path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video.mp4".to_string());
+    let tags: Vec<&str> = metadata.tags.iter()
+        .map(|tag| tag.trim())
+        .filter(|tag| (2..=30).contains(&tag.chars().count()))
+        .take(MAX_TAGS)
+        .collect();
+    let mut details = json!({
+        "name": text.title,
+        "channelId": config.channel_id,
+        "filename": file_name,
+        "privacy": config.privacy.id(),
+        "description": text.description,
+        "tags": tags,
+        "waitTranscoding": config.wait_transcoding,
+    });
+    if let Some(category) = config.category {
+        details["category"] = json!(category);
+    }
+    if let Some(licence) = config.licence {
+        details["licence"] = json!(licence);
+    }
+    if let Some(language) = &config.language {
+        details["language"] = json!(language);
+    }
+
+    let location = session.retry.run("Starting the upload", || async {
+        let res = session.authorized(session.client.post(session.url("api/v1/videos/upload-resumable")?))
+            .header("X-Upload-Content-Length", file_size)
+            .header("X-Upload-Content-Type", "video/mp4")
+            .json(&details)
+            .send()
+            .await?;
+        let res = check_status(res).await?;
+        res.headers().get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("PeerTube did not return an upload URL"))
+    }).await?;
+    // The instance answers with a scheme-relative URL
+    let upload_url = session.base_url.join(&location).context(format!("Invalid upload URL: {}", location))?;
+    Ok(upload_url.to_string())
+}
+
+// Covers cannot be sent with a resumable upload, so they are set on the finished video
+async fn set_thumbnail(session: &Session<'_>, video_id: i64, thumbnail: &str) -> Result<()> {
+    session.retry.run("Setting the thumbnail", || async {
+        let form = Form::new().file("thumbnailfile", thumbnail).await?;
+        let res = session.authorized(session.client.put(session.url(&format!("api/v1/videos/{}", video_id))?))
+            .multipart(form)
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }).await
+}
+
+// Returns the link to the uploaded video
+pub async fn upload_to_peertube(config: &PeertubeConfig, file_path: &str, metadata: &VideoMetadata, text: &PostText,
+                                retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    let base_url = Url::parse(&format!("{}/", config.base_url.trim_end_matches('/')))
+        .context(format!("Invalid PeerTube URL: {}", config.base_url))?;
+    let file_size = fs::metadata(file_path)
+        .map(|m| m.len())
+        .context("Failed to get file metadata")?;
+
+    let token = login(&client, &base_url, config, retry).await?;
+    let session = Session { client, base_url, token, retry };
+
+    let video = Resumable { client: &session.client, token: &session.token, refresh: None, retry, name: "PeerTube", chunk_size: CHUNK_SIZE }
+        .upload(file_path, || start_upload(&session, config, file_path, file_size, metadata, text))
+        .await?;
+    println!("PeerTube Upload Response: {}", video);
+
+    let video = &video["video"];
+    if let (Some(id), Some(thumbnail)) = (video["id"].as_i64(), &metadata.thumbnail_file) {
+        // The video is out already, a missing cover is not worth failing it
+        if let Err(e) = set_thumbnail(&session, id, thumbnail).await {
+            eprintln!("Failed to set the PeerTube thumbnail: {:#}", e);
+        }
+    }
+    let id = video["shortUUID"].as_str().or(video["uuid"].as_str());
+    Ok(id.map(|id| format!("{}w/{}", session.base_url, id)))
+}
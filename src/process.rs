@@ -1,33 +1,340 @@
+use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
+use anyhow::{anyhow, bail};
+use crate::announce;
+use crate::config::Config;
+use crate::jobs::{self, JobTracker};
+use crate::retry::Retry;
+use crate::schedule::{self, PublishAt};
+use crate::store::{JobStatus, Store};
+use crate::telegram;
+use crate::template::{self, Markup};
+use crate::titles;
 use crate::transform::{transform_video, EncodingPasses};
-use crate::upload;
-use crate::youtube::download_video;
+use crate::upload::{self, Post, Published, UploadOptions};
+use crate::youtube::{download_video, list_playlist, VideoMetadata};
 use crate::Platform;
 
-pub(crate) async fn youtube(
-    url: &str, platform: Platform, output: &str,
-    delete_youtube: bool, delete_transformed: bool, rutube_api_key: Option<String>,
-    bot_api_url: &str, max_file_size: u64, bot_token: Option<String>,
-    chat_id: Option<i64>, vk_access_token: Option<String>) -> anyhow::Result<()> {
+// Everything a run of the pipeline needs besides the URL
+#[derive(Clone)]
+pub(crate) struct ProcessOptions {
+    pub platforms: Vec<Platform>,
+    pub output: String,
+    pub delete_youtube: bool,
+    pub delete_transformed: bool,
+    pub upload: UploadOptions,
+    pub config: Arc<Config>,
+    pub profile: Option<String>,
+    // Hold the videos back for the scheduler instead of uploading right away
+    pub publish_at: Option<PublishAt>,
+}
 
-    println!("Starting process: Download -> Transform -> Upload");
+// A downloaded and transformed video, ready to be uploaded to any number of platforms
+pub(crate) struct Prepared {
+    pub downloaded_file: String,
+    pub transformed_file: String,
+    pub metadata: VideoMetadata,
+}
 
+pub(crate) fn prepare(url: &str, output: &str, job: &JobTracker) -> anyhow::Result<Prepared> {
     println!("Downloading from: {}", url);
-    let (downloaded_file, title) = download_video(&url, &output)?;
+    job.set_status(JobStatus::Downloading);
+    let metadata = download_video(url, output)?;
+    let downloaded_file = metadata.filename.clone();
     println!("Downloaded file: {:?}", downloaded_file);
+    job.set_title(&metadata.title);
+    job.downloaded(&metadata);
+    transform(metadata, job)
+}
 
+// Transforms a video that is already on disk at `metadata.filename`
+fn transform(metadata: VideoMetadata, job: &JobTracker) -> anyhow::Result<Prepared> {
+    let downloaded_file = metadata.filename.clone();
     println!("Transforming video: {}", downloaded_file);
+    job.set_status(JobStatus::Transforming);
     let transformed_file = transform_video(&downloaded_file, EncodingPasses::TwoPass)?;
     println!("Transformed video saved as: {}", transformed_file);
+    job.transformed(&metadata, &transformed_file);
 
-    upload::upload(platform, &transformed_file, &title, rutube_api_key, &bot_api_url,
-                   max_file_size, bot_token, chat_id, vk_access_token, url, "").await?;
+    Ok(Prepared { downloaded_file, transformed_file, metadata })
+}
 
+pub(crate) fn cleanup(prepared: &Prepared, delete_youtube: bool, delete_transformed: bool) -> anyhow::Result<()> {
     if delete_youtube {
-        fs::remove_file(&downloaded_file)?;
+        fs::remove_file(&prepared.downloaded_file)?;
     }
     if delete_transformed {
-        fs::remove_file(&transformed_file)?;
+        fs::remove_file(&prepared.transformed_file)?;
     }
     Ok(())
 }
+
+pub(crate) async fn youtube(url: &str, options: &ProcessOptions) -> anyhow::Result<()> {
+    let job = JobTracker::start(&options.config, url, &options.platforms);
+    youtube_job(url, options, &job).await
+}
+
+// Runs a job that has been recorded already, e.g. one queued through the API
+pub(crate) async fn youtube_job(url: &str, options: &ProcessOptions, job: &JobTracker) -> anyhow::Result<()> {
+    println!("Starting process: Download -> Transform -> Upload");
+
+    let result = async {
+        let (source, output, tracker) = (url.to_string(), options.output.clone(), job.clone());
+        let prepared = jobs::blocking(move || prepare(&source, &output, &tracker)).await?;
+        deliver(&prepared, url, options, job).await
+    }.await;
+    job.finish(&result);
+    result.map(|_| ())
+}
+
+// Runs a video that did not come from YouTube, like one sent to the bot, through the rest of
+// the pipeline. `metadata.filename` is the file; it is treated like a download.
+pub(crate) async fn file(metadata: VideoMetadata, options: &ProcessOptions) -> anyhow::Result<()> {
+    let job = JobTracker::start(&options.config, &metadata.filename, &options.platforms);
+    file_job(metadata, options, &job).await
+}
+
+pub(crate) async fn file_job(metadata: VideoMetadata, options: &ProcessOptions, job: &JobTracker) -> anyhow::Result<()> {
+    job.set_title(&metadata.title);
+    // The video was received rather than downloaded, but it is on disk from here on just the same
+    job.downloaded(&metadata);
+    let result = async {
+        let tracker = job.clone();
+        let prepared = jobs::blocking(move || transform(metadata, &tracker)).await?;
+        deliver(&prepared, "", options, job).await
+    }.await;
+    job.finish(&result);
+    result.map(|_| ())
+}
+
+// Publishes right away or hands the video to the scheduler, then removes what is no longer needed
+async fn deliver(prepared: &Prepared, url: &str, options: &ProcessOptions, job: &JobTracker) -> anyhow::Result<JobStatus> {
+    // The archive copy is stored right away, also for videos that are published later, unless an
+    // earlier run stored it already
+    if options.upload.s3.as_ref().is_some_and(|s3| s3.archive) && !options.platforms.contains(&Platform::S3)
+        && !Store::load(&options.config.store)?.is_published(&prepared.metadata.id, Platform::S3) {
+        job.set_status(JobStatus::Uploading);
+        publish_one(Platform::S3, &prepared.transformed_file, &prepared.metadata, url, options, &HashMap::new(),
+                    Some(job)).await?;
+    }
+    match options.publish_at {
+        Some(when) => {
+            let (now, later): (Vec<Platform>, Vec<Platform>) = options.platforms.iter()
+                .partition(|platform| platform.schedules_itself());
+            for platform in now {
+                let time = schedule::publish_time(&options.config, platform, when)?;
+                let options = ProcessOptions { publish_at: Some(PublishAt::Time(time)), ..options.clone() };
+                job.set_status(JobStatus::Uploading);
+                let links = Store::load(&options.config.store)?.links(&prepared.metadata.id);
+                publish_one(platform, &prepared.transformed_file, &prepared.metadata, url, &options, &links,
+                            Some(job)).await?;
+            }
+            if later.is_empty() {
+                cleanup(prepared, options.delete_youtube, options.delete_transformed)?;
+                return Ok(JobStatus::Done);
+            }
+            schedule::enqueue(prepared, url, &ProcessOptions { platforms: later, ..options.clone() }, when, job)?;
+            // The scheduler deletes the transformed file once the last post is released
+            cleanup(prepared, options.delete_youtube, false)?;
+            Ok(JobStatus::Scheduled)
+        }
+        None => {
+            job.set_status(JobStatus::Uploading);
+            publish(prepared, &options.platforms, url, options, job).await?;
+            cleanup(prepared, options.delete_youtube, options.delete_transformed)?;
+            Ok(JobStatus::Done)
+        }
+    }
+}
+
+// Uploads to the platforms in order, with the links of the copies published so far available
+// to the templates of the following ones, then posts to the notifiers and announces the video
+pub(crate) async fn publish(
+    prepared: &Prepared, platforms: &[Platform], url: &str, options: &ProcessOptions,
+    job: &JobTracker) -> anyhow::Result<Vec<Published>> {
+
+    // Links of copies published before, like the archive copy, are known from the start
+    let mut links = Store::load(&options.config.store)?.links(&prepared.metadata.id);
+    let mut published = Vec::new();
+    let mut platforms = platforms.to_vec();
+    platforms.sort_by_key(|platform| platform.is_notifier());
+    for platform in &platforms {
+        let result = publish_one(*platform, &prepared.transformed_file, &prepared.metadata, url, options, &links, Some(job)).await?;
+        if let Some(link) = &result.url {
+            links.insert(format!("{}_url", result.platform), link.clone());
+        }
+        published.push(result);
+    }
+    announce::announce(options, &prepared.metadata, url).await;
+    Ok(published)
+}
+
+// Rewrites the title for the platform, renders its texts from the template, uploads the file and
+// records the publication in the store. `links` holds the `{<platform>_url}` template variables;
+// retries of the upload are noted in `job`.
+pub(crate) async fn publish_one(
+    platform: Platform, file: &str, metadata: &VideoMetadata, url: &str, options: &ProcessOptions,
+    links: &HashMap<String, String>, job: Option<&JobTracker>) -> anyhow::Result<Published> {
+
+    let metadata = titles::rewrite(&options.config, options.profile.as_deref(), platform, metadata)?;
+    let mut vars = template::variables(&metadata, url);
+    vars.extend(links.clone());
+    let markup = match platform {
+        Platform::Telegram => options.config.telegram.parse_mode,
+        Platform::Matrix => options.upload.matrix.as_ref().map_or(Markup::Plain, |matrix| matrix.parse_mode),
+        _ => Markup::Plain,
+    };
+    let post = Post {
+        text: options.config.template(platform).render(platform, &vars, markup)?,
+        markup,
+        telegram: match platform {
+            Platform::Telegram => telegram::destinations(&options.config, options.upload.chat_id, &vars)?,
+            _ => Vec::new(),
+        },
+        publish_at: match options.publish_at {
+            Some(PublishAt::Time(time)) => Some(time),
+            _ => None,
+        },
+    };
+    let retry = Retry { platform, policy: options.config.retry.policy(platform), job };
+    let result = upload::upload(platform, file, &metadata, &post, &options.upload, &retry).await?;
+    if let Some(job) = job {
+        job.uploaded(&metadata, file, &result);
+    }
+
+    Store::update(&options.config.store, |store| {
+        store.record(&metadata.id, platform, &metadata.title, result.url.clone(), result.messages.clone(),
+                     post.publish_at);
+        Ok(())
+    })?;
+
+    // Buttons of an earlier Telegram post can now link to this copy
+    if let Some(link) = result.url.as_ref().filter(|_| platform != Platform::Telegram) {
+        vars.insert(format!("{}_url", platform), link.clone());
+        telegram::update_posts(&options.config, &options.upload, &metadata.id, &vars, None).await;
+    }
+    Ok(result)
+}
+
+// How a playlist or channel URL is turned into individual jobs
+pub(crate) struct BatchOptions {
+    // 1-based selection like `1-10`, `3,5,7` or `20-`, applied after ordering
+    pub items: Option<String>,
+    // Playlists list the newest video first; reversing publishes them in chronological order
+    pub reverse: bool,
+    // Title pattern with `{title}`, `{n}` and `{total}`, e.g. "{title} (Episode {n}/{total})"
+    pub title_format: Option<String>,
+}
+
+pub(crate) async fn batch(url: &str, options: &ProcessOptions, batch_options: &BatchOptions) -> anyhow::Result<()> {
+    let playlist = url.to_string();
+    let mut entries = jobs::blocking(move || list_playlist(&playlist)).await?;
+    if batch_options.reverse {
+        entries.reverse();
+    }
+    let selected = match &batch_options.items {
+        Some(spec) => parse_items(spec, entries.len())?,
+        None => (1..=entries.len()).collect(),
+    };
+    let total = selected.len();
+    println!("Processing {} of {} video(s) from {}", total, entries.len(), url);
+
+    let mut failures = Vec::new();
+    for (i, index) in selected.iter().enumerate() {
+        let entry = &entries[index - 1];
+        let n = i + 1;
+        println!("[{}/{}] {}", n, total, entry.title);
+
+        let job = JobTracker::start(&options.config, &entry.url, &options.platforms);
+        let result = async {
+            let mut prepared = prepare(&entry.url, &options.output, &job)?;
+            if let Some(format) = &batch_options.title_format {
+                prepared.metadata.title = format
+                    .replace("{title}", &prepared.metadata.title)
+                    .replace("{n}", &n.to_string())
+                    .replace("{total}", &total.to_string());
+            }
+            deliver(&prepared, &entry.url, options, &job).await
+        }.await;
+        job.finish(&result);
+
+        match result {
+            Ok(_) => println!("[{}/{}] Done", n, total),
+            Err(e) => {
+                eprintln!("[{}/{}] Failed: {:#}", n, total, e);
+                failures.push((n, entry.title.clone(), e));
+            }
+        }
+    }
+
+    println!("Batch finished: {} succeeded, {} failed", total - failures.len(), failures.len());
+    for (n, title, e) in &failures {
+        println!("  #{} {}: {:#}", n, title, e);
+    }
+    if !failures.is_empty() {
+        bail!("{} of {} video(s) failed", failures.len(), total);
+    }
+    Ok(())
+}
+
+// Parses a 1-based item selection into indices, keeping the given order and dropping duplicates
+fn parse_items(spec: &str, len: usize) -> anyhow::Result<Vec<usize>> {
+    let parse = |s: &str| s.trim().parse::<usize>().map_err(|_| anyhow!("Invalid item number: {}", s));
+    let mut indices = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (
+                if start.trim().is_empty() { 1 } else { parse(start)? },
+                if end.trim().is_empty() { len } else { parse(end)?.min(len) },
+            ),
+            None => (parse(part)?, parse(part)?),
+        };
+        if start == 0 {
+            bail!("Items are numbered from 1: {}", part);
+        }
+        for index in start..=end {
+            if index <= len && !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+    }
+    if indices.is_empty() {
+        bail!("Items {} select none of the {} item(s)", spec, len);
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_item_selections() {
+        assert_eq!(parse_items("1-10", 20).unwrap(), (1..=10).collect::<Vec<_>>());
+        assert_eq!(parse_items("3,5,7", 20).unwrap(), vec![3, 5, 7]);
+        assert_eq!(parse_items("18-", 20).unwrap(), vec![18, 19, 20]);
+        assert_eq!(parse_items("-3", 20).unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_items(" 7 , 2-3 ,", 20).unwrap(), vec![7, 2, 3]);
+    }
+
+    #[test]
+    fn drops_duplicates_and_items_past_the_end() {
+        assert_eq!(parse_items("2,1-3,2", 20).unwrap(), vec![2, 1, 3]);
+        assert_eq!(parse_items("4-50", 5).unwrap(), vec![4, 5]);
+        assert_eq!(parse_items("3,9", 5).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn rejects_bad_selections() {
+        assert!(parse_items("0", 20).is_err());
+        assert!(parse_items("0-3", 20).is_err());
+        assert!(parse_items("a-3", 20).is_err());
+        assert!(parse_items("1,x", 20).is_err());
+        // Nothing left to process
+        assert!(parse_items("20-", 10).is_err());
+        assert!(parse_items("11", 10).is_err());
+        assert!(parse_items("5-3", 10).is_err());
+        assert!(parse_items("", 10).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use indicatif::{ProgressBar, ProgressStyle, WeakProgressBar};
+use reqwest::Body;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf, Take};
+use tokio_util::io::ReaderStream;
+use crate::jobs;
+
+// Bars of the steps that are running with the job they belong to, so the API can tell how far a job is
+static ACTIVE: Mutex<Vec<(Option<u64>, WeakProgressBar)>> = Mutex::new(Vec::new());
+
+// How far the step of a job that started last has got
+#[derive(Serialize, Clone, PartialEq)]
+pub(crate) struct Progress {
+    pub message: String,
+    pub position: u64,
+    // Unknown for spinners
+    pub total: Option<u64>,
+}
+
+fn register(pb: &ProgressBar) {
+    let mut active = ACTIVE.lock().expect("progress list lock");
+    active.retain(|(_, bar)| bar.upgrade().is_some_and(|bar| !bar.is_finished()));
+    active.push((jobs::current(), pb.downgrade()));
+}
+
+pub(crate) fn of(job: u64) -> Option<Progress> {
+    let active = ACTIVE.lock().expect("progress list lock");
+    active.iter().rev()
+        .filter(|(id, _)| *id == Some(job))
+        .filter_map(|(_, bar)| bar.upgrade())
+        .find(|bar| !bar.is_finished())
+        .map(|bar| Progress { message: bar.message(), position: bar.position(), total: bar.length() })
+}
+
+// For steps whose length is unknown, like yt-dlp and ffmpeg runs
+pub(crate) fn spinner(message: impl Into<String>) -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(ProgressStyle::default_spinner()
+        .template("{spinner} {msg}")
+        .expect("valid template"));
+    pb.set_message(message.into());
+    pb.enable_steady_tick(Duration::from_millis(100));
+    register(&pb);
+    pb
+}
+
+// For transfers of a known number of bytes
+pub(crate) fn bytes(total: u64, message: impl Into<String>) -> ProgressBar {
+    let pb = ProgressBar::new(total);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner} {msg} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec} ({eta})")
+        .expect("valid template")
+        .progress_chars("=> "));
+    pb.set_message(message.into());
+    pb.enable_steady_tick(Duration::from_millis(100));
+    register(&pb);
+    pb
+}
+
+// Advances a progress bar by every byte read through it
+pub(crate) struct ProgressReader<R> {
+    inner: R,
+    pb: ProgressBar,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.pb.inc((buf.filled().len() - before) as u64);
+        }
+        result
+    }
+}
+
+// Opens `len` bytes of a file from `start` for streaming. The bar is moved back to `start` so
+// a retried request does not count the same bytes twice.
+pub(crate) async fn open(path: &str, start: u64, len: u64, pb: &ProgressBar) -> Result<ProgressReader<Take<File>>> {
+    let mut file = File::open(path).await.context(format!("Failed to open file: {}", path))?;
+    file.seek(SeekFrom::Start(start)).await.context("Failed to seek in the file")?;
+    pb.set_position(start);
+    Ok(ProgressReader { inner: file.take(len), pb: pb.clone() })
+}
+
+// A request body that reads from disk as it is sent, so memory use does not grow with the file
+pub(crate) fn body<R: AsyncRead + Send + Unpin + 'static>(reader: R) -> Body {
+    Body::wrap_stream(ReaderStream::new(reader))
+}
@@ -0,0 +1,193 @@
+use std::fs;
+use std::future::Future;
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::BoxFuture;
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::error::UploadError;
+use crate::progress;
+use crate::retry::{check_status, HttpError, Retry};
+
+// Fetches a new access token, for servers whose tokens can expire during a long upload
+pub(crate) type Refresh<'a> = dyn Fn() -> BoxFuture<'a, Result<String>> + Send + Sync + 'a;
+
+// The resumable upload protocol of YouTube and PeerTube: one request creates the upload, then the
+// file is sent in `Content-Range` chunks. The server confirms each with 308 and a `Range` header
+// until it answers the last one with the finished video.
+pub(crate) struct Resumable<'a> {
+    pub client: &'a Client,
+    pub token: &'a str,
+    // Used once a chunk is turned down with 401
+    pub refresh: Option<&'a Refresh<'a>>,
+    pub retry: &'a Retry<'a>,
+    // Platform name for messages, e.g. "PeerTube"
+    pub name: &'a str,
+    pub chunk_size: u64,
+}
+
+// Progress of an upload, kept next to the video so an interrupted upload continues where it
+// stopped on the next run
+#[derive(Serialize, Deserialize)]
+struct UploadState {
+    upload_url: String,
+    file_size: u64,
+    // Bytes the server has confirmed
+    offset: u64,
+}
+
+impl UploadState {
+    fn path(file_path: &str, retry: &Retry<'_>) -> String {
+        format!("{}.{}-upload", file_path, retry.platform)
+    }
+
+    // A saved state only counts if it was made for the same file
+    fn load(path: &str, file_size: u64) -> Option<UploadState> {
+        let content = fs::read_to_string(path).ok()?;
+        let state: UploadState = serde_json::from_str(&content).ok()?;
+        (state.file_size == file_size).then_some(state)
+    }
+
+    fn save(&self, path: &str) -> Result<()> {
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_string(self)?).context("Failed to write upload state")?;
+        fs::rename(&tmp, path).context("Failed to replace upload state")?;
+        Ok(())
+    }
+}
+
+// Whether the server turned the request down for its access token
+fn unauthorized(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<HttpError>().is_some_and(|e| e.status == StatusCode::UNAUTHORIZED))
+}
+
+// The end of the confirmed range in a `Range: bytes=0-N` header
+fn confirmed(res: &Response) -> Option<u64> {
+    res.headers().get(reqwest::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|range| range.rsplit('-').next())
+        .and_then(|end| end.trim().parse::<u64>().ok())
+        .map(|end| end + 1)
+}
+
+impl Resumable<'_> {
+    // Continues a saved upload of `file_path`, or creates one with `start`, which returns the URL
+    // the chunks go to. Returns the server's answer to the last chunk.
+    pub(crate) async fn upload<F>(&self, file_path: &str, start: impl FnOnce() -> F) -> Result<Value>
+    where F: Future<Output = Result<String>> {
+        let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+        let state_path = UploadState::path(file_path, self.retry);
+
+        let (mut state, resumed) = match UploadState::load(&state_path, file_size) {
+            Some(mut state) => {
+                let (offset, video) = self.query_offset(&state).await?;
+                // The last chunk arrived before the run was interrupted
+                if offset >= file_size {
+                    fs::remove_file(&state_path).ok();
+                    return video.ok_or_else(|| anyhow!("The {} upload is complete, but the server did not return the video",
+                                                       self.name));
+                }
+                state.offset = offset;
+                println!("Resuming {} upload at {} of {} bytes", self.name, state.offset, file_size);
+                (state, true)
+            }
+            None => {
+                let state = UploadState { upload_url: start().await?, file_size, offset: 0 };
+                state.save(&state_path)?;
+                (state, false)
+            }
+        };
+
+        match self.send_chunks(file_path, &state_path, &mut state).await {
+            Ok(video) => {
+                fs::remove_file(&state_path).ok();
+                Ok(video)
+            }
+            Err(e) => {
+                // A server that refuses a resumed upload has most likely dropped it, so the next
+                // run starts over instead of failing the same way
+                if resumed && !UploadError::classify(self.retry.platform, &e).retryable() {
+                    fs::remove_file(&state_path).ok();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Asks how much of an interrupted upload the server has, and the video if it has all of it
+    async fn query_offset(&self, state: &UploadState) -> Result<(u64, Option<Value>)> {
+        self.retry.run("Checking the upload", || async {
+            let res = self.client.put(&state.upload_url)
+                .bearer_auth(self.token)
+                .header("Content-Range", format!("bytes */{}", state.file_size))
+                .header("Content-Length", 0)
+                .send()
+                .await?;
+            if res.status() == StatusCode::PERMANENT_REDIRECT {
+                return Ok((confirmed(&res).unwrap_or(0), None));
+            }
+            let video = check_status(res).await?.json::<Value>().await.ok();
+            Ok((state.file_size, video))
+        }).await
+    }
+
+    async fn send_chunks(&self, file_path: &str, state_path: &str, state: &mut UploadState) -> Result<Value> {
+        let pb = progress::bytes(state.file_size, format!("Uploading to {}", self.name));
+        pb.set_position(state.offset);
+        let mut token = self.token.to_string();
+        let mut refreshed = false;
+        // Chunks in a row the server took none of
+        let mut stalled = 0;
+
+        loop {
+            let start = state.offset;
+            let end = (start + self.chunk_size).min(state.file_size).saturating_sub(1);
+            let len = (end + 1).saturating_sub(start);
+
+            let sent = self.retry.run(&format!("Uploading bytes {}-{}", start, end), || async {
+                let reader = progress::open(file_path, start, len, &pb).await?;
+                let res = self.client.put(&state.upload_url)
+                    .bearer_auth(&token)
+                    .header("Content-Type", "application/octet-stream")
+                    .header("Content-Length", len)
+                    .header("Content-Range", format!("bytes {}-{}/{}", start, end, state.file_size))
+                    .body(progress::body(reader))
+                    .send()
+                    .await?;
+                // 308 means the server wants the next chunk; without a `Range` it has none of this one
+                if res.status() == StatusCode::PERMANENT_REDIRECT {
+                    return Ok((confirmed(&res).unwrap_or(start), None));
+                }
+                let video = check_status(res).await?.json::<Value>().await?;
+                Ok((state.file_size, Some(video)))
+            }).await;
+            let (offset, video) = match (sent, self.refresh) {
+                // The token ran out while the upload went on; the chunk is sent again with a new one
+                (Err(e), Some(refresh)) if !refreshed && unauthorized(&e) => {
+                    println!("The {} access token expired, requesting a new one", self.name);
+                    token = refresh().await?;
+                    refreshed = true;
+                    continue;
+                }
+                (sent, _) => sent?,
+            };
+            refreshed = false;
+            if video.is_none() && offset <= start {
+                stalled += 1;
+                if stalled >= self.retry.policy.max_attempts {
+                    bail!("{} did not take any of bytes {}-{}", self.name, start, end);
+                }
+            } else {
+                stalled = 0;
+            }
+
+            state.offset = offset;
+            state.save(state_path)?;
+            pb.set_position(state.offset);
+            if let Some(video) = video {
+                pb.finish_with_message("Upload complete");
+                return Ok(video);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use anyhow::Result;
+use rand::Rng;
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
+use teloxide::RequestError;
+use crate::error::UploadError;
+use crate::jobs::JobTracker;
+use crate::Platform;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct RetryPolicy {
+    // Including the first attempt
+    pub max_attempts: u32,
+    pub initial_delay_secs: f64,
+    pub max_delay_secs: f64,
+    pub multiplier: f64,
+    // Fraction of the delay that is randomly added or taken away
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_delay_secs: 5.0,
+            max_delay_secs: 300.0,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+// Retry policies, with per-platform overrides of the default one
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct RetryConfig {
+    pub default: RetryPolicy,
+    pub platforms: HashMap<Platform, RetryPolicy>,
+}
+
+impl RetryConfig {
+    pub(crate) fn policy(&self, platform: Platform) -> &RetryPolicy {
+        self.platforms.get(&platform).unwrap_or(&self.default)
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let delay = (self.initial_delay_secs * self.multiplier.powi(attempt as i32 - 1)).min(self.max_delay_secs);
+        let jitter = delay * self.jitter.clamp(0.0, 1.0);
+        let delay = delay + rand::thread_rng().gen_range(-jitter..=jitter);
+        Duration::from_secs_f64(delay.max(0.0))
+    }
+}
+
+// A non-success HTTP response, keeping what is needed to decide on a retry
+#[derive(Debug)]
+pub(crate) struct HttpError {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+// Turns a non-success response into an `HttpError`
+pub(crate) async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // Only the delay-seconds form of Retry-After is used by the platforms we talk to
+    let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+    Err(HttpError { status, retry_after, body }.into())
+}
+
+// The delay the server asked for, if any
+fn retry_after(error: &anyhow::Error) -> Option<Duration> {
+    error.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<HttpError>() {
+            return e.retry_after;
+        }
+        match cause.downcast_ref::<RequestError>() {
+            Some(RequestError::RetryAfter(seconds)) => Some(seconds.duration()),
+            _ => None,
+        }
+    })
+}
+
+// Retry state of one upload
+pub(crate) struct Retry<'a> {
+    pub platform: Platform,
+    pub policy: &'a RetryPolicy,
+    pub job: Option<&'a JobTracker>,
+}
+
+impl Retry<'_> {
+    // Runs `operation` until it succeeds, fails permanently or runs out of attempts
+    pub(crate) async fn run<T, F, Fut>(&self, what: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let error = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if !UploadError::classify(self.platform, &error).retryable() || attempt >= self.policy.max_attempts {
+                return Err(error);
+            }
+            let delay = retry_after(&error).unwrap_or_else(|| self.policy.backoff(attempt));
+            eprintln!("{} on {} failed (attempt {}/{}), retrying in {:.0}s: {:#}",
+                      what, self.platform, attempt, self.policy.max_attempts, delay.as_secs_f64(), error);
+            if let Some(job) = self.job {
+                job.retry(self.platform, attempt, &error, delay);
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
@@ -1,19 +1,51 @@
-use anyhow::Result;
-use reqwest::{Client, multipart::Form};
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use reqwest::{Client, multipart::{Form, Part}};
+use serde_json::Value;
+use crate::progress;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
+use crate::youtube::VideoMetadata;
 
-pub async fn upload_to_rutube(api_key: &str, file_path: &str, _title: &str) -> Result<()> {
+// Returns the link to the uploaded video if Rutube reports its ID
+pub async fn upload_to_rutube(api_key: &str, file_path: &str, metadata: &VideoMetadata, text: &PostText,
+                              retry: &Retry<'_>) -> Result<Option<String>> {
     let client = Client::new();
+    let file_size = fs::metadata(file_path).map(|m| m.len()).context("Failed to get file metadata")?;
+    let file_name = Path::new(file_path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video.mp4".to_string());
+    let pb = progress::bytes(file_size, "Uploading to Rutube");
 
-    let form = Form::new()
-        .file("video", file_path).await?;
+    let body = retry.run("Uploading the video", || async {
+        let reader = progress::open(file_path, 0, file_size, &pb).await?;
+        let video = Part::stream_with_length(progress::body(reader), file_size)
+            .file_name(file_name.clone())
+            .mime_str("video/mp4")?;
+        let mut form = Form::new()
+            .text("title", text.title.clone())
+            .text("description", text.description.clone())
+            .part("video", video);
+        if !metadata.tags.is_empty() {
+            form = form.text("tags", metadata.tags.join(","));
+        }
+        if let Some(thumbnail) = &metadata.thumbnail_file {
+            form = form.file("thumbnail", thumbnail).await?;
+        }
 
-    let res = client
-        .post("https://rutube.ru/api/video/upload/")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .multipart(form)
-        .send()
-        .await?;
+        let res = client
+            .post("https://rutube.ru/api/video/upload/")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .multipart(form)
+            .send()
+            .await?;
+        Ok(check_status(res).await?.text().await?)
+    }).await?;
+    pb.finish_with_message("Upload complete");
+    println!("Upload response: {:?}", body);
 
-    println!("Upload response: {:?}", res.text().await?);
-    Ok(())
+    let json: Value = serde_json::from_str(&body).unwrap_or_default();
+    let id = json["video_id"].as_str().or(json["id"].as_str());
+    Ok(id.map(|id| format!("https://rutube.ru/video/{}/", id)))
 }
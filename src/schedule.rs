@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use crate::announce;
+use crate::config::Config;
+use crate::jobs::JobTracker;
+use crate::process::{self, Prepared, ProcessOptions};
+use crate::store::{JobStatus, ScheduledPost, Store};
+use crate::Platform;
+
+// Failed releases are retried with a growing delay and dropped after this many attempts
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ScheduleConfig {
+    // Offset of the time zone that slots and `--publish-at` are given in, e.g. "+03:00" for Moscow
+    pub utc_offset: String,
+    pub platforms: HashMap<Platform, PlatformSlots>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            utc_offset: "+00:00".to_string(),
+            platforms: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct PlatformSlots {
+    // Posting times as HH:MM
+    pub slots: Vec<String>,
+    pub max_per_day: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PublishAt {
+    // The first free slot of each platform
+    NextSlot,
+    // Unix timestamp in seconds
+    Time(i64),
+}
+
+impl ScheduleConfig {
+    fn offset(&self) -> Result<FixedOffset> {
+        let time = DateTime::parse_from_rfc3339(&format!("2000-01-01T00:00:00{}", self.utc_offset))
+            .context(format!("Invalid UTC offset: {}", self.utc_offset))?;
+        Ok(*time.offset())
+    }
+
+    // Accepts RFC 3339 or "YYYY-MM-DD HH:MM" in the configured time zone
+    pub(crate) fn parse_time(&self, text: &str) -> Result<i64> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            return Ok(time.timestamp());
+        }
+        let local = NaiveDateTime::parse_from_str(text.trim(), "%Y-%m-%d %H:%M")
+            .context(format!("Invalid time, expected YYYY-MM-DD HH:MM: {}", text))?;
+        self.offset()?.from_local_datetime(&local)
+            .single()
+            .map(|time| time.timestamp())
+            .ok_or_else(|| anyhow!("Invalid time: {}", text))
+    }
+
+    pub(crate) fn format_time(&self, timestamp: i64) -> String {
+        match (self.offset(), DateTime::from_timestamp(timestamp, 0)) {
+            (Ok(offset), Some(time)) => time.with_timezone(&offset).format("%Y-%m-%d %H:%M").to_string(),
+            _ => timestamp.to_string(),
+        }
+    }
+
+    // The first slot after `now` that is neither taken nor on a day that is already full
+    fn next_slot(&self, platform: Platform, taken: &[i64], now: i64) -> Result<i64> {
+        let slots = self.platforms.get(&platform)
+            .filter(|p| !p.slots.is_empty())
+            .ok_or_else(|| anyhow!("No posting slots configured for {}", platform))?;
+        let mut times = slots.slots.iter()
+            .map(|s| NaiveTime::parse_from_str(s, "%H:%M").context(format!("Invalid slot: {}", s)))
+            .collect::<Result<Vec<_>>>()?;
+        times.sort();
+
+        let offset = self.offset()?;
+        let today = DateTime::from_timestamp(now, 0).unwrap_or_default().with_timezone(&offset).date_naive();
+        let max_per_day = slots.max_per_day.unwrap_or(usize::MAX);
+        for date in today.iter_days().take(366) {
+            let used = taken.iter()
+                .filter_map(|t| DateTime::from_timestamp(*t, 0))
+                .filter(|t| t.with_timezone(&offset).date_naive() == date)
+                .count();
+            for time in &times {
+                let Some(slot) = offset.from_local_datetime(&date.and_time(*time)).single() else {
+                    continue;
+                };
+                let slot = slot.timestamp();
+                if used >= max_per_day {
+                    break;
+                }
+                if slot > now && !taken.contains(&slot) {
+                    return Ok(slot);
+                }
+            }
+        }
+        bail!("No free posting slot for {} within a year", platform)
+    }
+}
+
+// The time a post goes out: the one given, or the next free slot of the platform
+fn slot(config: &Config, store: &Store, platform: Platform, when: PublishAt, now: i64) -> Result<i64> {
+    match when {
+        PublishAt::Time(time) => Ok(time),
+        PublishAt::NextSlot => {
+            // Posts that are out already count towards the limit of their day too, and copies
+            // uploaded ahead of time hold their slot
+            let taken: Vec<i64> = store.scheduled.iter()
+                .filter(|p| p.platform == platform)
+                .map(|p| p.publish_at)
+                .chain(store.publications.iter()
+                    .filter(|p| p.platform == platform)
+                    .map(|p| p.publish_at.unwrap_or(p.published_at as i64)))
+                .collect();
+            config.schedule.next_slot(platform, &taken, now)
+        }
+    }
+}
+
+// The time a video goes out on a platform that publishes it by itself
+pub(crate) fn publish_time(config: &Config, platform: Platform, when: PublishAt) -> Result<i64> {
+    slot(config, &Store::load(&config.store)?, platform, when, Utc::now().timestamp())
+}
+
+// Holds the prepared video back and queues one post per platform
+pub(crate) fn enqueue(
+    prepared: &Prepared, url: &str, options: &ProcessOptions, when: PublishAt, job: &JobTracker) -> Result<Vec<ScheduledPost>> {
+    let config = &options.config;
+    let now = Utc::now().timestamp();
+    Store::update(&config.store, |store| {
+        let mut posts = Vec::new();
+        for platform in &options.platforms {
+            let publish_at = slot(config, store, *platform, when, now)?;
+            let post = ScheduledPost {
+                id: store.next_id(),
+                platform: *platform,
+                file: prepared.transformed_file.clone(),
+                metadata: prepared.metadata.clone(),
+                source_url: url.to_string(),
+                profile: options.profile.clone(),
+                publish_at,
+                delete_file: options.delete_transformed,
+                job_id: Some(job.id),
+                attempts: 0,
+                last_error: None,
+            };
+            println!("Scheduled '{}' on {} for {}", post.metadata.title, platform,
+                     config.schedule.format_time(publish_at));
+            store.scheduled.push(post.clone());
+            posts.push(post);
+        }
+        Ok(posts)
+    })
+}
+
+// Removes a scheduled post; the file is kept in case it is still needed
+pub(crate) fn unschedule(config: &Config, id: u64) -> Result<ScheduledPost> {
+    Store::update(&config.store, |store| {
+        let index = store.scheduled.iter().position(|p| p.id == id)
+            .ok_or_else(|| anyhow!("No scheduled post with ID {}", id))?;
+        Ok(store.scheduled.remove(index))
+    })
+}
+
+pub(crate) fn reorder(config: &Config, id: u64, position: usize) -> Result<()> {
+    Store::update(&config.store, |store| move_post(&mut store.scheduled, id, position))
+}
+
+// Moves a post to the given 1-based position among the posts of its platform. The posts keep
+// the set of times they occupied and get them reassigned in the new order.
+fn move_post(scheduled: &mut [ScheduledPost], id: u64, position: usize) -> Result<()> {
+    let platform = scheduled.iter().find(|p| p.id == id)
+        .map(|p| p.platform)
+        .ok_or_else(|| anyhow!("No scheduled post with ID {}", id))?;
+    let mut posts: Vec<&mut ScheduledPost> = scheduled.iter_mut()
+        .filter(|p| p.platform == platform)
+        .collect();
+    posts.sort_by_key(|p| p.publish_at);
+    let mut times: Vec<i64> = posts.iter().map(|p| p.publish_at).collect();
+    times.sort();
+
+    let from = posts.iter().position(|p| p.id == id).unwrap_or(0);
+    let to = position.clamp(1, posts.len()) - 1;
+    let post = posts.remove(from);
+    posts.insert(to, post);
+    for (post, time) in posts.into_iter().zip(times) {
+        post.publish_at = time;
+    }
+    Ok(())
+}
+
+// Scheduled posts ordered by time
+pub(crate) fn list(config: &Config) -> Result<Vec<ScheduledPost>> {
+    let mut posts = Store::load(&config.store)?.scheduled;
+    posts.sort_by_key(|p| p.publish_at);
+    Ok(posts)
+}
+
+pub(crate) fn describe(config: &Config, post: &ScheduledPost) -> String {
+    let mut line = format!("#{} {} {} {}", post.id, config.schedule.format_time(post.publish_at),
+                           post.platform, post.metadata.title);
+    if let Some(error) = &post.last_error {
+        line.push_str(&format!(" (attempt {} failed: {})", post.attempts, error));
+    }
+    line
+}
+
+pub(crate) async fn run(options: ProcessOptions) {
+    loop {
+        if let Err(e) = release_due(&options).await {
+            eprintln!("Scheduler failed: {:#}", e);
+        }
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+async fn release_due(options: &ProcessOptions) -> Result<()> {
+    let path = &options.config.store;
+    let now = Utc::now().timestamp();
+    let scheduled = Store::load(path)?.scheduled;
+    let due: Vec<ScheduledPost> = scheduled.iter()
+        .filter(|p| p.publish_at <= now)
+        // Notifiers wait until the copies they link to are out
+        .filter(|p| !p.platform.is_notifier() || !scheduled.iter()
+            .any(|other| other.metadata.id == p.metadata.id && !other.platform.is_notifier()))
+        .cloned()
+        .collect();
+
+    for post in due {
+        println!("Releasing scheduled post #{} to {}", post.id, post.platform);
+        let store = Store::load(path)?;
+        let links = store.links(&post.metadata.id);
+        let options = &ProcessOptions { profile: post.profile.clone(), publish_at: None, ..options.clone() };
+        let job = post.job_id.map(|id| JobTracker::resume(&options.config, id));
+        if let Some(job) = &job {
+            job.set_status(JobStatus::Uploading);
+        }
+        // E.g. by a run that was stopped before it could remove the post
+        let result = if store.is_published(&post.metadata.id, post.platform) {
+            println!("'{}' is on {} already", post.metadata.title, post.platform);
+            Ok(None)
+        } else {
+            process::publish_one(post.platform, &post.file, &post.metadata, &post.source_url, options, &links,
+                                 job.as_ref()).await.map(Some)
+        };
+
+        let (remove_file, announce, waiting) = Store::update(path, |store| {
+            match &result {
+                Ok(_) => store.scheduled.retain(|p| p.id != post.id),
+                Err(e) => {
+                    eprintln!("Failed to release scheduled post #{}: {:#}", post.id, e);
+                    if let Some(p) = store.scheduled.iter_mut().find(|p| p.id == post.id) {
+                        p.attempts += 1;
+                        p.last_error = Some(format!("{:#}", e));
+                        p.publish_at = now + 300 * p.attempts as i64;
+                    }
+                    if post.attempts + 1 >= MAX_ATTEMPTS {
+                        eprintln!("Giving up on scheduled post #{}", post.id);
+                        store.scheduled.retain(|p| p.id != post.id);
+                    }
+                }
+            }
+            let remove_file = post.delete_file && !store.scheduled.iter().any(|p| p.file == post.file);
+            // The last copy of the video is out
+            let announce = result.is_ok() && !store.scheduled.iter().any(|p| p.metadata.id == post.metadata.id);
+            let waiting = store.scheduled.iter().any(|p| p.job_id.is_some() && p.job_id == post.job_id);
+            Ok((remove_file, announce, waiting))
+        })?;
+        // The job is over once none of its posts are left; it failed if the last one was given up on
+        let job_result = match result {
+            _ if waiting => Ok(JobStatus::Scheduled),
+            Ok(_) => Ok(JobStatus::Done),
+            Err(e) => Err(e),
+        };
+        if announce {
+            announce::announce(options, &post.metadata, &post.source_url).await;
+        }
+        if let Some(job) = &job {
+            job.finish(&job_result);
+        }
+        if remove_file {
+            fs::remove_file(&post.file).ok();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::youtube::VideoMetadata;
+
+    fn post(id: u64, platform: Platform, publish_at: i64) -> ScheduledPost {
+        ScheduledPost {
+            id,
+            platform,
+            file: String::new(),
+            metadata: VideoMetadata::default(),
+            source_url: String::new(),
+            profile: None,
+            publish_at,
+            delete_file: false,
+            job_id: None,
+            attempts: 0,
+            last_error: None,
+        }
+    }
+
+    fn order(posts: &[ScheduledPost], platform: Platform) -> Vec<(u64, i64)> {
+        let mut order: Vec<_> = posts.iter().filter(|p| p.platform == platform).map(|p| (p.id, p.publish_at)).collect();
+        order.sort_by_key(|(_, time)| *time);
+        order
+    }
+
+    fn queue() -> Vec<ScheduledPost> {
+        vec![post(1, Platform::Vk, 100), post(2, Platform::Telegram, 150), post(3, Platform::Vk, 200),
+             post(4, Platform::Vk, 300), post(5, Platform::Telegram, 250)]
+    }
+
+    #[test]
+    fn moves_a_post_forward_and_back() {
+        let mut posts = queue();
+        move_post(&mut posts, 4, 1).unwrap();
+        assert_eq!(order(&posts, Platform::Vk), vec![(4, 100), (1, 200), (3, 300)]);
+        move_post(&mut posts, 4, 3).unwrap();
+        assert_eq!(order(&posts, Platform::Vk), vec![(1, 100), (3, 200), (4, 300)]);
+        // Other platforms keep their times
+        assert_eq!(order(&posts, Platform::Telegram), vec![(2, 150), (5, 250)]);
+    }
+
+    #[test]
+    fn clamps_the_position() {
+        let mut posts = queue();
+        move_post(&mut posts, 1, 10).unwrap();
+        assert_eq!(order(&posts, Platform::Vk), vec![(3, 100), (4, 200), (1, 300)]);
+        move_post(&mut posts, 1, 0).unwrap();
+        assert_eq!(order(&posts, Platform::Vk), vec![(1, 100), (3, 200), (4, 300)]);
+    }
+
+    #[test]
+    fn rejects_unknown_posts() {
+        let mut posts = queue();
+        assert!(move_post(&mut posts, 9, 1).is_err());
+        assert_eq!(order(&posts, Platform::Vk), vec![(1, 100), (3, 200), (4, 300)]);
+    }
+}
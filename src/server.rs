@@ -0,0 +1,725 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use axum::extract::{Extension, FromRequest, Multipart, Path as UrlPath, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use base64::Engine;
+use clap::ValueEnum;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
+use tokio::task::AbortHandle;
+use crate::error;
+use crate::jobs::{self, JobTracker};
+use crate::process::{self, ProcessOptions};
+use crate::progress::{self, Progress};
+use crate::schedule::{self, PublishAt};
+use crate::store::{self, ApiRequest, Job, JobStatus, Store};
+use crate::sync;
+use crate::youtube::VideoMetadata;
+use crate::Platform;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct ServerConfig {
+    // Address the API and the dashboard listen on
+    pub listen: String,
+    // Bearer tokens for scripts; they have admin rights
+    pub tokens: Vec<String>,
+    // Dashboard accounts, signed in with HTTP basic auth
+    pub users: Vec<User>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "127.0.0.1:8080".to_string(),
+            tokens: Vec::new(),
+            users: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct User {
+    pub name: String,
+    pub password: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+// Read-only users follow jobs and the schedule; admins also submit, cancel and retry
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Role {
+    Admin,
+    #[default]
+    ReadOnly,
+}
+
+// Who made a request, as found by `authorize`
+#[derive(Clone)]
+struct Caller {
+    name: String,
+    role: Role,
+    // Signed in with basic auth, which browsers also send along with requests other sites make
+    browser: bool,
+}
+
+// Where the video of a job comes from
+enum Source {
+    Url(String),
+    File(Box<VideoMetadata>),
+}
+
+struct Queued {
+    source: Source,
+    options: ProcessOptions,
+}
+
+// Jobs run one at a time in the order they were submitted, like the bot handles the links it gets.
+// The store has the final say: a job only runs while it is recorded as queued, and queued jobs
+// are picked up again after a restart.
+struct Server {
+    // Defaults for jobs that do not say otherwise
+    options: ProcessOptions,
+    // IDs of the jobs in the order they were queued
+    queue: mpsc::UnboundedSender<u64>,
+    // What the queued jobs run on, taken by the worker when it gets to them
+    pending: Mutex<HashMap<u64, Queued>>,
+    // The job that is being processed and the handle to stop it
+    running: Mutex<Option<(u64, AbortHandle)>>,
+    // The store as last read for the event streams, which all share it
+    snapshot: watch::Sender<Arc<Store>>,
+}
+
+impl Server {
+    fn running_id(&self) -> Option<u64> {
+        self.running.lock().expect("running job lock").as_ref().map(|(id, _)| *id)
+    }
+
+    fn enqueue(&self, source: Source, options: ProcessOptions) -> u64 {
+        let url = match &source {
+            Source::Url(url) => url.clone(),
+            Source::File(metadata) => metadata.filename.clone(),
+        };
+        let job = JobTracker::start(&options.config, &url, &options.platforms);
+        job.set_request(ApiRequest { profile: options.profile.clone(), publish_at: options.publish_at });
+        // Uploaded files are only known by their path, so their title is kept from the start
+        if let Source::File(metadata) = &source {
+            job.set_title(&metadata.title);
+        }
+        self.push(job.id, Queued { source, options });
+        job.id
+    }
+
+    fn push(&self, id: u64, queued: Queued) {
+        self.pending.lock().expect("pending jobs lock").insert(id, queued);
+        self.queue.send(id).ok();
+    }
+
+    // Source and options of a stored job, to run it again
+    fn restore(&self, job: &Job) -> Result<Queued, ApiError> {
+        let source = if job.url.starts_with("http://") || job.url.starts_with("https://") {
+            Source::Url(job.url.clone())
+        } else if Path::new(&job.url).exists() {
+            Source::File(Box::new(file_metadata(&job.url, job.title.clone().unwrap_or_default())))
+        } else {
+            return Err(ApiError::conflict(format!("The file of job #{} is gone", job.id)));
+        };
+        let options = match &job.request {
+            Some(request) => ProcessOptions {
+                platforms: job.platforms.clone(),
+                profile: request.profile.clone(),
+                publish_at: request.publish_at,
+                ..self.options.clone()
+            },
+            None => self.job_options(Some(job.platforms.clone()), None, None, false)?,
+        };
+        Ok(Queued { source, options })
+    }
+
+    // Queues the jobs that were waiting when the server stopped, and fails the ones it stopped in
+    // the middle of, since their uploads may have been half done
+    fn recover(&self) -> anyhow::Result<()> {
+        let store = Store::load(&self.options.config.store)?;
+        let mut queued = 0;
+        for job in store.jobs.iter().filter(|job| job.request.is_some()) {
+            let tracker = JobTracker::resume(&self.options.config, job.id);
+            let scheduled = store.scheduled.iter().any(|post| post.job_id == Some(job.id));
+            match job.status {
+                JobStatus::Queued => match self.restore(job) {
+                    Ok(restored) => {
+                        self.push(job.id, restored);
+                        queued += 1;
+                    }
+                    Err(e) => tracker.finish(&Err(anyhow!(e.1))),
+                },
+                // The scheduler carries on with jobs whose posts are still waiting
+                JobStatus::Downloading | JobStatus::Transforming | JobStatus::Uploading if !scheduled => {
+                    tracker.finish(&Err(anyhow!("The server stopped while the job was {}, retry it to run it again",
+                                                job.status)));
+                }
+                _ => {}
+            }
+        }
+        if queued > 0 {
+            println!("Queued {} job(s) left from the last run", queued);
+        }
+        Ok(())
+    }
+
+    // Options of one job: the server's defaults with what the request overrides
+    fn job_options(&self, platforms: Option<Vec<Platform>>, profile: Option<String>, publish_at: Option<String>,
+                   schedule: bool) -> Result<ProcessOptions, ApiError> {
+        let config = &self.options.config;
+        if let Some(profile) = profile.as_ref().filter(|profile| !config.profiles.contains_key(*profile)) {
+            return Err(ApiError::bad_request(format!("Unknown profile: {}", profile)));
+        }
+        let publish_at = match publish_at {
+            Some(time) => Some(PublishAt::Time(config.schedule.parse_time(&time).map_err(ApiError::bad_request)?)),
+            None if schedule => Some(PublishAt::NextSlot),
+            None => self.options.publish_at,
+        };
+        let platforms = platforms.unwrap_or_else(|| self.options.platforms.clone());
+        if platforms.is_empty() {
+            return Err(ApiError::bad_request("No platforms given"));
+        }
+        Ok(ProcessOptions {
+            platforms,
+            profile: profile.or(self.options.profile.clone()),
+            publish_at,
+            ..self.options.clone()
+        })
+    }
+}
+
+// An error answered as `{"error": "..."}`
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: impl ToString) -> ApiError {
+        ApiError(StatusCode::BAD_REQUEST, message.to_string())
+    }
+
+    fn unauthorized() -> ApiError {
+        ApiError(StatusCode::UNAUTHORIZED, "Missing or unknown credentials".to_string())
+    }
+
+    fn not_found(id: u64) -> ApiError {
+        ApiError(StatusCode::NOT_FOUND, format!("No job with ID {}", id))
+    }
+
+    fn conflict(message: impl ToString) -> ApiError {
+        ApiError(StatusCode::CONFLICT, message.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.0, Json(json!({ "error": self.1 }))).into_response();
+        // Makes the browser ask for the dashboard user and password
+        if self.0 == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE,
+                                          header::HeaderValue::from_static("Basic realm=\"video-publisher\""));
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", error))
+    }
+}
+
+// A job with how far its current step has got, while it runs, and where it was published
+#[derive(Serialize)]
+struct JobView {
+    #[serde(flatten)]
+    job: Job,
+    progress: Option<Progress>,
+    links: Vec<Link>,
+}
+
+#[derive(Serialize)]
+struct Link {
+    platform: Platform,
+    url: String,
+}
+
+fn view(store: &Store, job: Job) -> JobView {
+    let progress = progress::of(job.id);
+    let links = store.publications.iter()
+        .filter(|p| job.video_id.as_ref() == Some(&p.video_id) && job.platforms.contains(&p.platform))
+        .filter_map(|p| p.url.clone().map(|url| Link { platform: p.platform, url }))
+        .collect();
+    JobView { job, progress, links }
+}
+
+fn find_job(server: &Server, id: u64) -> Result<Job, ApiError> {
+    Store::load(&server.options.config.store)?.jobs.into_iter()
+        .find(|job| job.id == id)
+        .ok_or(ApiError::not_found(id))
+}
+
+#[derive(Deserialize)]
+struct JobRequest {
+    url: String,
+    platforms: Option<Vec<Platform>>,
+    profile: Option<String>,
+    // "YYYY-MM-DD HH:MM" in the configured time zone, or RFC 3339
+    publish_at: Option<String>,
+    // Publish in the next free posting slot of each platform
+    #[serde(default)]
+    schedule: bool,
+}
+
+// A JSON body with a `url`, or a multipart form with the video as `file`
+async fn submit(State(server): State<Arc<Server>>, request: Request) -> Result<impl IntoResponse, ApiError> {
+    let multipart = request.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"));
+    let id = if multipart {
+        let form = Multipart::from_request(request, &()).await.map_err(|e| ApiError::bad_request(e.body_text()))?;
+        submit_file(&server, form).await?
+    } else {
+        let Json(body) = Json::<JobRequest>::from_request(request, &()).await
+            .map_err(|e| ApiError::bad_request(e.body_text()))?;
+        let options = server.job_options(body.platforms, body.profile, body.publish_at, body.schedule)?;
+        server.enqueue(Source::Url(body.url), options)
+    };
+    Ok((StatusCode::ACCEPTED, Json(json!({ "id": id }))))
+}
+
+// The stored file is removed again if the form turns out to be invalid
+async fn submit_file(server: &Server, form: Multipart) -> Result<u64, ApiError> {
+    let mut file = None;
+    let result = read_form(server, form, &mut file).await;
+    if let (Err(_), Some((path, _))) = (&result, &file) {
+        tokio::fs::remove_file(path).await.ok();
+    }
+    result
+}
+
+// Numbers the files uploaded through the API
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+// `file` is set to the stored video and its original name as soon as it is on disk
+async fn read_form(server: &Server, mut form: Multipart, file: &mut Option<(String, String)>) -> Result<u64, ApiError> {
+    let mut fields = HashMap::new();
+    while let Some(mut field) = form.next_field().await.map_err(|e| ApiError::bad_request(e.body_text()))? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name != "file" {
+            fields.insert(name, field.text().await.map_err(|e| ApiError::bad_request(e.body_text()))?);
+            continue;
+        }
+        if file.is_some() {
+            return Err(ApiError::bad_request("The form has more than one file"));
+        }
+        // The original name is kept for the title, but cannot pick a directory
+        let original = field.file_name().unwrap_or("video.mp4").to_string();
+        let safe: String = original.chars()
+            .map(|c| if c.is_alphanumeric() || ".-_".contains(c) { c } else { '_' })
+            .collect();
+        let output = &server.options.output;
+        tokio::fs::create_dir_all(output).await.context(format!("Failed to create directory: {}", output))?;
+        // Uploads in the same second get different numbers, and an existing file is never replaced
+        let (path, mut out) = loop {
+            let path = Path::new(output).join(format!("upload-{}-{}-{}", store::now(), UPLOADS.fetch_add(1, Ordering::Relaxed),
+                                                      safe.trim_start_matches('.')));
+            match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+                Ok(out) => break (path, out),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(anyhow::Error::new(e).context(format!("Failed to create file: {}", path.display())).into()),
+            }
+        };
+        *file = Some((path.to_string_lossy().to_string(), original.clone()));
+        while let Some(chunk) = field.chunk().await.map_err(|e| ApiError::bad_request(e.body_text()))? {
+            out.write_all(&chunk).await.context(format!("Failed to write {}", path.display()))?;
+        }
+        out.flush().await.context(format!("Failed to write {}", path.display()))?;
+    }
+    let (path, original) = file.clone().ok_or_else(|| ApiError::bad_request("The form has no file"))?;
+
+    let platforms = fields.remove("platforms")
+        .map(|list| list.split(',').map(str::trim).filter(|p| !p.is_empty())
+            .map(|p| serde_json::from_value(json!(p)).map_err(|_| ApiError::bad_request(format!("Unknown platform: {}", p))))
+            .collect::<Result<Vec<Platform>, _>>())
+        .transpose()?;
+    let schedule = fields.get("schedule").is_some_and(|v| v == "true" || v == "on" || v == "1");
+    let options = server.job_options(platforms, fields.remove("profile").filter(|v| !v.is_empty()),
+                                     fields.remove("publish_at").filter(|v| !v.is_empty()), schedule)?;
+    let title = fields.remove("title").filter(|title| !title.trim().is_empty())
+        .unwrap_or_else(|| Path::new(&original).file_stem().unwrap_or_default().to_string_lossy().to_string());
+    Ok(server.enqueue(Source::File(Box::new(file_metadata(&path, title))), options))
+}
+
+// Uploaded files have no ID of their own, the stored file's name stands in for it
+fn file_metadata(path: &str, title: String) -> VideoMetadata {
+    VideoMetadata {
+        id: Path::new(path).file_stem().unwrap_or_default().to_string_lossy().to_string(),
+        title,
+        filename: path.to_string(),
+        ..Default::default()
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+}
+
+async fn list(State(server): State<Arc<Server>>, Query(query): Query<ListQuery>) -> Result<Json<Vec<JobView>>, ApiError> {
+    let store = Store::load(&server.options.config.store)?;
+    let jobs = jobs::recent(&server.options.config, query.limit.unwrap_or(50))?;
+    Ok(Json(jobs.into_iter().map(|job| view(&store, job)).collect()))
+}
+
+async fn status(State(server): State<Arc<Server>>, UrlPath(id): UrlPath<u64>) -> Result<Json<JobView>, ApiError> {
+    let store = Store::load(&server.options.config.store)?;
+    let job = find_job(&server, id)?;
+    Ok(Json(view(&store, job)))
+}
+
+// A scheduled post with its time in the configured time zone
+#[derive(Serialize)]
+struct ScheduledView {
+    id: u64,
+    job_id: Option<u64>,
+    platform: Platform,
+    title: String,
+    source_url: String,
+    publish_at: i64,
+    // "YYYY-MM-DD HH:MM"
+    time: String,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+async fn scheduled(State(server): State<Arc<Server>>) -> Result<Json<Vec<ScheduledView>>, ApiError> {
+    let config = &server.options.config;
+    let posts = schedule::list(config)?;
+    Ok(Json(posts.into_iter().map(|post| ScheduledView {
+        id: post.id,
+        job_id: post.job_id,
+        platform: post.platform,
+        title: post.metadata.title,
+        source_url: post.source_url,
+        publish_at: post.publish_at,
+        time: config.schedule.format_time(post.publish_at),
+        attempts: post.attempts,
+        last_error: post.last_error,
+    }).collect()))
+}
+
+// The signed-in user and what the submit form offers
+async fn me(State(server): State<Arc<Server>>, Extension(caller): Extension<Caller>) -> Json<serde_json::Value> {
+    let mut profiles: Vec<&String> = server.options.config.profiles.keys().collect();
+    profiles.sort();
+    Json(json!({
+        "name": caller.name,
+        "role": caller.role,
+        "platforms": Platform::value_variants(),
+        "default_platforms": server.options.platforms,
+        "profiles": profiles,
+    }))
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+async fn cancel(State(server): State<Arc<Server>>, UrlPath(id): UrlPath<u64>) -> Result<Json<JobView>, ApiError> {
+    let tracker = JobTracker::resume(&server.options.config, id);
+    {
+        // Held while the stored status is read, so the worker cannot start the job meanwhile
+        let running = server.running.lock().expect("running job lock");
+        let job = find_job(&server, id)?;
+        match (running.as_ref().filter(|(running, _)| *running == id), job.status) {
+            (Some((_, handle)), _) => {
+                // Marked first, so the failure of the stopped step is not recorded
+                tracker.cancel();
+                // yt-dlp and ffmpeg do not notice the abort, they are stopped on their own
+                error::kill_tools(id);
+                handle.abort();
+            }
+            (None, JobStatus::Queued) => {
+                server.pending.lock().expect("pending jobs lock").remove(&id);
+                tracker.cancel();
+            }
+            (None, JobStatus::Scheduled) => {
+                Store::update(&server.options.config.store, |store| {
+                    store.scheduled.retain(|post| post.job_id != Some(id));
+                    Ok(())
+                })?;
+                tracker.cancel();
+            }
+            (None, status) => {
+                return Err(ApiError::conflict(format!("Job #{} is {} and cannot be cancelled", id, status)));
+            }
+        }
+    }
+    // A running job is reported once it has stopped
+    while server.running_id() == Some(id) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    println!("Cancelled job #{}", id);
+    status(State(server), UrlPath(id)).await
+}
+
+// Runs a failed or cancelled job again with its URL or file, platforms and request
+async fn retry(State(server): State<Arc<Server>>, UrlPath(id): UrlPath<u64>) -> Result<Json<JobView>, ApiError> {
+    let job = find_job(&server, id)?;
+    if !matches!(job.status, JobStatus::Failed | JobStatus::Cancelled) {
+        return Err(ApiError::conflict(format!("Job #{} is {}, only failed or cancelled jobs can be retried", id, job.status)));
+    }
+    let queued = server.restore(&job)?;
+    JobTracker::resume(&server.options.config, id).requeue();
+    server.push(id, queued);
+    status(State(server), UrlPath(id)).await
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    // Only this job
+    job: Option<u64>,
+}
+
+// `job` events whenever a job changes and `progress` events while a job moves
+async fn events(State(server): State<Arc<Server>>, Query(query): Query<EventsQuery>)
+    -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    // Jobs are reported as they are now, and after that only when they change
+    let mut seen: HashMap<u64, (JobStatus, u64)> = HashMap::new();
+    let mut first = true;
+    let mut last_progress: HashMap<u64, Progress> = HashMap::new();
+    let mut snapshot = server.snapshot.subscribe();
+
+    let stream = async_stream(move |sender| async move {
+        loop {
+            // Stop as soon as the client is gone, so the store is not read for nobody
+            tokio::select! {
+                changed = snapshot.changed() => if changed.is_err() {
+                    return;
+                },
+                _ = sender.closed() => return,
+            }
+            let store = snapshot.borrow_and_update().clone();
+            let mut moved = Vec::new();
+            for job in store.jobs.iter().filter(|job| query.job.is_none_or(|id| id == job.id)) {
+                match progress::of(job.id) {
+                    Some(progress) if last_progress.get(&job.id) != Some(&progress) => {
+                        last_progress.insert(job.id, progress.clone());
+                        moved.push((job.id, progress));
+                    }
+                    Some(_) => {}
+                    None => {
+                        last_progress.remove(&job.id);
+                    }
+                }
+                let state = (job.status, job.updated_at);
+                let changed = seen.insert(job.id, state) != Some(state);
+                let active = !matches!(job.status, JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled);
+                if changed && (!first || active) {
+                    let event = SseEvent::default().event("job").json_data(view(&store, job.clone())).expect("a job serializes");
+                    if sender.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            first = false;
+
+            for (id, progress) in moved {
+                let data = json!({ "id": id, "progress": progress });
+                if sender.send(Ok(SseEvent::default().event("progress").data(data.to_string()))).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// Reads the store once a second for as long as any event stream is open
+async fn watch_store(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if server.snapshot.receiver_count() == 0 {
+            continue;
+        }
+        match Store::load(&server.options.config.store) {
+            Ok(store) => {
+                server.snapshot.send_replace(Arc::new(store));
+            }
+            Err(e) => eprintln!("Failed to read jobs for the event stream: {:#}", e),
+        }
+    }
+}
+
+// A stream fed by a task; the task ends once the client is gone
+fn async_stream<T, F, Fut>(producer: F) -> impl Stream<Item = T>
+where
+    T: Send + 'static,
+    F: FnOnce(mpsc::Sender<T>) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(producer(sender));
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+}
+
+// Compares without stopping at the first difference, so timing does not give the token away
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// The dashboard user whose `name:password` is base64-encoded in `credentials`
+fn basic_user<'a>(config: &'a ServerConfig, credentials: &str) -> Option<&'a User> {
+    let decoded = base64::engine::general_purpose::STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    config.users.iter().find(|user| same(&user.name, name) && same(&user.password, password))
+}
+
+async fn authorize(State(server): State<Arc<Server>>, mut request: Request, next: Next) -> Result<Response, ApiError> {
+    let config = &server.options.config.server;
+    let authorization = request.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let bearer = authorization.and_then(|v| v.strip_prefix("Bearer "));
+    // Browsers cannot set headers on an event stream, so the token may come in the query
+    let query = Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok()
+        .and_then(|Query(mut query)| query.remove("access_token"));
+    let query = query.as_deref();
+    let caller = match (bearer.or(query), authorization.and_then(|v| v.strip_prefix("Basic "))) {
+        (Some(token), _) if config.tokens.iter().any(|known| same(known, token)) => {
+            Caller { name: "token".to_string(), role: Role::Admin, browser: false }
+        }
+        (_, Some(credentials)) => match basic_user(config, credentials) {
+            Some(user) => Caller { name: user.name.clone(), role: user.role, browser: true },
+            None => return Err(ApiError::unauthorized()),
+        },
+        _ => return Err(ApiError::unauthorized()),
+    };
+    request.extensions_mut().insert(caller);
+    Ok(next.run(request).await)
+}
+
+// Runs after `authorize` on the routes that change jobs
+async fn admin_only(Extension(caller): Extension<Caller>, request: Request, next: Next) -> Result<Response, ApiError> {
+    if caller.role != Role::Admin {
+        return Err(ApiError(StatusCode::FORBIDDEN, format!("{} is not allowed to change jobs", caller.name)));
+    }
+    if caller.browser && !same_site(&request) {
+        return Err(ApiError(StatusCode::FORBIDDEN, "Cross-site requests cannot change jobs".to_string()));
+    }
+    Ok(next.run(request).await)
+}
+
+// Other sites can make a browser send forms, but not custom headers without a CORS preflight the
+// server never allows; the dashboard sets `X-Requested-With`. A given Origin has to be this host too.
+fn same_site(request: &Request) -> bool {
+    let headers = request.headers();
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+    let origin = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok())
+        .map(|origin| origin.split_once("://").map_or(origin, |(_, rest)| rest));
+    headers.contains_key("x-requested-with") && origin.is_none_or(|origin| Some(origin) == host)
+}
+
+// Takes jobs off the queue one by one
+async fn work(server: Arc<Server>, mut queue: mpsc::UnboundedReceiver<u64>) {
+    while let Some(id) = queue.recv().await {
+        // A job queued twice, e.g. retried before its first turn, is run once
+        let Some(Queued { source, options }) = server.pending.lock().expect("pending jobs lock").remove(&id) else {
+            continue;
+        };
+        let task = {
+            let mut running = server.running.lock().expect("running job lock");
+            // Cancelled while it waited
+            if !find_job(&server, id).is_ok_and(|job| job.status == JobStatus::Queued) {
+                continue;
+            }
+            let job = JobTracker::resume(&options.config, id);
+            let task = tokio::spawn(jobs::scope(id, async move {
+                match source {
+                    Source::Url(url) => process::youtube_job(&url, &options, &job).await,
+                    Source::File(metadata) => process::file_job(*metadata, &options, &job).await,
+                }
+            }));
+            *running = Some((id, task.abort_handle()));
+            task
+        };
+        match task.await {
+            _ if find_job(&server, id).is_ok_and(|job| job.status == JobStatus::Cancelled) => {}
+            Ok(Ok(())) => println!("Job #{} finished", id),
+            Ok(Err(e)) => eprintln!("Job #{} failed: {:#}", id, e),
+            Err(e) => eprintln!("Job #{} crashed: {}", id, e),
+        }
+        *server.running.lock().expect("running job lock") = None;
+    }
+}
+
+pub(crate) async fn run(options: ProcessOptions, listen: Option<String>) -> anyhow::Result<()> {
+    let config = &options.config.server;
+    if config.tokens.is_empty() && config.users.is_empty() {
+        return Err(anyhow!("Set server.tokens or server.users in the config, the API does not run without authentication"));
+    }
+    let listen = listen.unwrap_or_else(|| config.listen.clone());
+
+    let sync = &options.config.sync;
+    if !sync.sources.is_empty() {
+        println!("Mirroring {} source(s) every {} minute(s)", sync.sources.len(), sync.interval_minutes);
+        tokio::spawn(sync::run_forever(options.clone()));
+    }
+    tokio::spawn(schedule::run(options.clone()));
+
+    let (queue, receiver) = mpsc::unbounded_channel();
+    let server = Arc::new(Server {
+        options,
+        queue,
+        pending: Mutex::new(HashMap::new()),
+        running: Mutex::new(None),
+        snapshot: watch::Sender::new(Arc::new(Store::default())),
+    });
+    server.recover()?;
+    tokio::spawn(work(server.clone(), receiver));
+    tokio::spawn(watch_store(server.clone()));
+
+    let admin = Router::new()
+        .route("/jobs", post(submit))
+        .route("/jobs/:id/cancel", post(cancel))
+        .route("/jobs/:id/retry", post(retry))
+        .route_layer(middleware::from_fn(admin_only));
+    let api = Router::new()
+        .route("/jobs", get(list))
+        .route("/jobs/:id", get(status))
+        .route("/schedule", get(scheduled))
+        .route("/me", get(me))
+        .route("/events", get(events))
+        .merge(admin);
+    let app = Router::new()
+        .route("/", get(dashboard))
+        .nest("/api", api)
+        .route_layer(middleware::from_fn_with_state(server.clone(), authorize))
+        // Uploaded videos can be large
+        .layer(axum::extract::DefaultBodyLimit::disable())
+        .with_state(server);
+
+    let listener = tokio::net::TcpListener::bind(&listen).await.context(format!("Failed to listen on {}", listen))?;
+    println!("Serving the dashboard and the API on http://{}", listen);
+    axum::serve(listener, app).await.context("The API server failed")?;
+    Ok(())
+}
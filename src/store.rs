@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::error::ErrorReport;
+use crate::schedule::PublishAt;
+use crate::youtube::VideoMetadata;
+use crate::Platform;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Publication {
+    pub video_id: String,
+    pub platform: Platform,
+    pub title: String,
+    // Unix timestamp in seconds
+    pub published_at: u64,
+    // When the platform makes the video public by itself, for copies uploaded ahead of time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_at: Option<i64>,
+    #[serde(default)]
+    pub url: Option<String>,
+    // Telegram messages carrying the video
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<MessageRef>,
+}
+
+// A posted Telegram message, kept so its buttons can be updated once the other uploads finish
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct MessageRef {
+    pub chat_id: i64,
+    pub message_id: i32,
+}
+
+// A transformed video waiting for its time slot on one platform
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ScheduledPost {
+    pub id: u64,
+    pub platform: Platform,
+    // Transformed file that is uploaded once the post is due
+    pub file: String,
+    pub metadata: VideoMetadata,
+    pub source_url: String,
+    pub profile: Option<String>,
+    // Unix timestamp in seconds
+    pub publish_at: i64,
+    // Remove `file` once no other post refers to it
+    pub delete_file: bool,
+    // Job that created the post
+    #[serde(default)]
+    pub job_id: Option<u64>,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    Queued,
+    Downloading,
+    Transforming,
+    Uploading,
+    Scheduled,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Downloading => "downloading",
+            JobStatus::Transforming => "transforming",
+            JobStatus::Uploading => "uploading",
+            JobStatus::Scheduled => "scheduled",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct RetryRecord {
+    pub platform: Platform,
+    pub attempt: u32,
+    // Machine code of the error, see `ErrorReport`
+    #[serde(default)]
+    pub code: String,
+    pub error: String,
+    pub delay_secs: f64,
+}
+
+// One URL going through the pipeline
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Job {
+    pub id: u64,
+    pub url: String,
+    pub platforms: Vec<Platform>,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub title: Option<String>,
+    // ID of the downloaded video, which its publications are recorded under
+    #[serde(default)]
+    pub video_id: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub error: Option<ErrorReport>,
+    #[serde(default)]
+    pub retries: Vec<RetryRecord>,
+    // Set for jobs queued through the API, so the server can pick them up again after a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<ApiRequest>,
+}
+
+// What a job queued through the API asked for besides its URL and platforms
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ApiRequest {
+    pub profile: Option<String>,
+    pub publish_at: Option<PublishAt>,
+}
+
+// Persistent state kept in a single JSON file next to the videos
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Store {
+    #[serde(skip)]
+    path: String,
+    #[serde(default)]
+    pub publications: Vec<Publication>,
+    #[serde(default)]
+    pub scheduled: Vec<ScheduledPost>,
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+    #[serde(default)]
+    pub next_id: u64,
+}
+
+// The bot runs the scheduler, sync and message handlers side by side, so every change goes
+// through `Store::update` to keep them from overwriting each other. The lock file next to the
+// store does the same for separate processes, such as `serve` and `schedule run` on one store.
+static LOCK: Mutex<()> = Mutex::new(());
+
+impl Store {
+    pub(crate) fn load(path: &str) -> Result<Store> {
+        let mut store = if Path::new(path).exists() {
+            let content = fs::read_to_string(path)
+                .context(format!("Failed to read store: {}", path))?;
+            serde_json::from_str(&content).context(format!("Failed to parse store: {}", path))?
+        } else {
+            Store::default()
+        };
+        store.path = path.to_string();
+        Ok(store)
+    }
+
+    // Loads the store, applies `change` and saves the result unless `change` fails
+    pub(crate) fn update<T>(path: &str, change: impl FnOnce(&mut Store) -> Result<T>) -> Result<T> {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let lock = format!("{}.lock", path);
+        let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock)
+            .context(format!("Failed to open store lock: {}", lock))?;
+        // Released when `file` is dropped
+        file.lock().context(format!("Failed to lock store: {}", lock))?;
+        let mut store = Store::load(path)?;
+        let result = change(&mut store)?;
+        store.save()?;
+        Ok(result)
+    }
+
+    fn save(&self) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a truncated store behind
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, serde_json::to_string_pretty(self)?).context("Failed to write store")?;
+        fs::rename(&tmp, &self.path).context("Failed to replace store")?;
+        Ok(())
+    }
+
+    // Drops the oldest finished jobs so that at most `keep` of them remain
+    pub(crate) fn prune_jobs(&mut self, keep: usize) {
+        let finished = |job: &Job| matches!(job.status, JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled);
+        let mut extra = self.jobs.iter().filter(|job| finished(job)).count().saturating_sub(keep);
+        // Jobs are kept in the order they were created
+        self.jobs.retain(|job| {
+            if extra > 0 && finished(job) {
+                extra -= 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    pub(crate) fn is_published(&self, video_id: &str, platform: Platform) -> bool {
+        self.publications.iter().any(|p| p.video_id == video_id && p.platform == platform)
+    }
+
+    pub(crate) fn is_scheduled(&self, video_id: &str, platform: Platform) -> bool {
+        self.scheduled.iter().any(|p| p.metadata.id == video_id && p.platform == platform)
+    }
+
+    // `{<platform>_url}` template variables with the links of the published copies of a video
+    pub(crate) fn links(&self, video_id: &str) -> HashMap<String, String> {
+        self.publications.iter()
+            .filter(|p| p.video_id == video_id)
+            .filter_map(|p| p.url.clone().map(|url| (format!("{}_url", p.platform), url)))
+            .collect()
+    }
+
+    pub(crate) fn record(&mut self, video_id: &str, platform: Platform, title: &str, url: Option<String>,
+                         messages: Vec<MessageRef>, publish_at: Option<i64>) {
+        if self.is_published(video_id, platform) {
+            return;
+        }
+        self.publications.push(Publication {
+            video_id: video_id.to_string(),
+            platform,
+            title: title.to_string(),
+            published_at: now(),
+            publish_at,
+            url,
+            messages,
+        });
+    }
+
+    pub(crate) fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64, status: JobStatus) -> Job {
+        Job {
+            id,
+            url: String::new(),
+            platforms: Vec::new(),
+            status,
+            title: None,
+            video_id: None,
+            created_at: 0,
+            updated_at: 0,
+            error: None,
+            retries: Vec::new(),
+            request: None,
+        }
+    }
+
+    #[test]
+    fn prune_drops_the_oldest_finished_jobs() {
+        let mut store = Store {
+            jobs: vec![
+                job(1, JobStatus::Done),
+                job(2, JobStatus::Scheduled),
+                job(3, JobStatus::Failed),
+                job(4, JobStatus::Cancelled),
+                job(5, JobStatus::Uploading),
+                job(6, JobStatus::Done),
+            ],
+            ..Store::default()
+        };
+        store.prune_jobs(2);
+        let ids: Vec<u64> = store.jobs.iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![2, 4, 5, 6]);
+        store.prune_jobs(0);
+        let ids: Vec<u64> = store.jobs.iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![2, 5]);
+    }
+}
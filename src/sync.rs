@@ -0,0 +1,166 @@
+use std::time::Duration;
+use anyhow::Result;
+use crate::config::{ShortsFilter, SyncFilter, SyncSource};
+use crate::jobs;
+use crate::process::{self, ProcessOptions};
+use crate::store::Store;
+use crate::youtube::{list_playlist, PlaylistEntry};
+
+impl SyncFilter {
+    // Values that yt-dlp does not report in flat mode never exclude a video
+    pub(crate) fn matches(&self, entry: &PlaylistEntry) -> bool {
+        match self.shorts {
+            ShortsFilter::Only if !entry.is_short => return false,
+            ShortsFilter::Exclude if entry.is_short => return false,
+            _ => {}
+        }
+        if let Some(duration) = entry.duration {
+            if self.min_duration.is_some_and(|min| duration < min)
+                || self.max_duration.is_some_and(|max| duration > max) {
+                return false;
+            }
+        }
+        let title = entry.title.to_lowercase();
+        if !self.keywords.is_empty() && !self.keywords.iter().any(|k| title.contains(&k.to_lowercase())) {
+            return false;
+        }
+        if self.exclude_keywords.iter().any(|k| title.contains(&k.to_lowercase())) {
+            return false;
+        }
+        if let (Some(after), Some(date)) = (&self.published_after, &entry.upload_date) {
+            // Both dates are YYYYMMDD, so string order is date order
+            if date.as_str() < after.as_str() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Publishes the new videos of one source and returns how many were published.
+// With `seed` the videos are only recorded as published, which is useful when adding
+// a channel whose back catalogue should not be mirrored.
+async fn sync_source(source: &SyncSource, options: &ProcessOptions, seed: bool) -> Result<usize> {
+    println!("Checking {}", source.url);
+    let url = source.url.clone();
+    let entries = jobs::blocking(move || list_playlist(&url)).await?;
+    let store = Store::load(&options.config.store)?;
+    let mut published = 0;
+
+    for entry in entries.iter().filter(|e| source.filter.matches(e)) {
+        let pending: Vec<_> = source.platforms.iter()
+            .copied()
+            .filter(|p| !store.is_published(&entry.id, *p) && !store.is_scheduled(&entry.id, *p))
+            .collect();
+        if pending.is_empty() {
+            continue;
+        }
+
+        if seed {
+            Store::update(&options.config.store, |store| {
+                for platform in &pending {
+                    store.record(&entry.id, *platform, &entry.title, None, Vec::new(), None);
+                }
+                Ok(())
+            })?;
+            continue;
+        }
+
+        println!("New video: {} ({})", entry.title, entry.url);
+        let options = &ProcessOptions {
+            platforms: pending,
+            profile: source.profile.clone(),
+            ..options.clone()
+        };
+        // Every upload is recorded in the store as soon as it succeeds, so a failure
+        // later on does not publish the video twice
+        process::youtube(&entry.url, options).await?;
+        published += 1;
+    }
+    Ok(published)
+}
+
+pub(crate) async fn run_once(options: &ProcessOptions, seed: bool) -> Result<usize> {
+    let mut published = 0;
+    for source in &options.config.sync.sources {
+        // A broken source must not keep the others from being mirrored
+        match sync_source(source, options, seed).await {
+            Ok(count) => published += count,
+            Err(e) => eprintln!("Failed to sync {}: {:#}", source.url, e),
+        }
+    }
+    Ok(published)
+}
+
+pub(crate) async fn run_forever(options: ProcessOptions) {
+    let interval = Duration::from_secs(options.config.sync.interval_minutes.max(1) * 60);
+    loop {
+        match run_once(&options, false).await {
+            Ok(count) => println!("Sync finished, {} new video(s) published", count),
+            Err(e) => eprintln!("Sync failed: {:#}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(title: &str, duration: Option<f64>, upload_date: Option<&str>, is_short: bool) -> PlaylistEntry {
+        PlaylistEntry {
+            id: "id".to_string(),
+            title: title.to_string(),
+            url: String::new(),
+            duration,
+            upload_date: upload_date.map(str::to_string),
+            is_short,
+        }
+    }
+
+    #[test]
+    fn filters_shorts() {
+        let short = entry("Clip", Some(30.0), None, true);
+        let video = entry("Talk", Some(600.0), None, false);
+        let filter = |shorts| SyncFilter { shorts, ..Default::default() };
+        assert!(filter(ShortsFilter::All).matches(&short) && filter(ShortsFilter::All).matches(&video));
+        assert!(filter(ShortsFilter::Only).matches(&short) && !filter(ShortsFilter::Only).matches(&video));
+        assert!(!filter(ShortsFilter::Exclude).matches(&short) && filter(ShortsFilter::Exclude).matches(&video));
+    }
+
+    #[test]
+    fn filters_duration() {
+        let filter = SyncFilter { min_duration: Some(60.0), max_duration: Some(3600.0), ..Default::default() };
+        assert!(!filter.matches(&entry("a", Some(59.0), None, false)));
+        assert!(filter.matches(&entry("a", Some(60.0), None, false)));
+        assert!(filter.matches(&entry("a", Some(3600.0), None, false)));
+        assert!(!filter.matches(&entry("a", Some(3601.0), None, false)));
+        // Unknown durations pass
+        assert!(filter.matches(&entry("a", None, None, false)));
+    }
+
+    #[test]
+    fn filters_keywords_ignoring_case() {
+        let filter = SyncFilter {
+            keywords: vec!["podcast".to_string(), "Interview".to_string()],
+            exclude_keywords: vec!["TRAILER".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&entry("The PODCAST #12", None, None, false)));
+        assert!(filter.matches(&entry("An interview with Ann", None, None, false)));
+        assert!(!filter.matches(&entry("Vlog", None, None, false)));
+        assert!(!filter.matches(&entry("Podcast trailer", None, None, false)));
+        assert!(!SyncFilter { exclude_keywords: vec!["live".to_string()], ..Default::default() }
+            .matches(&entry("Live stream", None, None, false)));
+    }
+
+    #[test]
+    fn filters_publish_date() {
+        let filter = SyncFilter { published_after: Some("20240301".to_string()), ..Default::default() };
+        assert!(!filter.matches(&entry("a", None, Some("20240229"), false)));
+        assert!(filter.matches(&entry("a", None, Some("20240301"), false)));
+        assert!(filter.matches(&entry("a", None, Some("20250101"), false)));
+        // Unknown dates pass
+        assert!(filter.matches(&entry("a", None, None, false)));
+    }
+}
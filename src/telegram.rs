@@ -1,91 +1,644 @@
 use anyhow::{Result, Context};
 use teloxide::prelude::*;
-use teloxide::types::{ChatId, InputFile};
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
-use std::fs::metadata;
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode, ReplyParameters,
+                      ThreadId};
+use teloxide::{ApiError, RequestError};
+use std::collections::HashMap;
+use std::fs::{self, metadata};
+use std::path::Path;
+use anyhow::{anyhow, bail};
+use futures_util::StreamExt;
+use reqwest::Url;
+use serde::Deserialize;
+use teloxide::net::Download;
+use teloxide::payloads::{SendMessage, SendVideo};
+use teloxide::requests::{JsonRequest, MultipartRequest};
+use tokio::io::AsyncWriteExt;
 use teloxide::net;
+use crate::config::Config;
+use crate::error::UploadError;
+use crate::progress;
+use crate::retry::Retry;
+use crate::store::{MessageRef, Store};
+use crate::template::{self, Markup, PostText, TemplateConfig};
+use crate::upload::{Post, UploadOptions};
+use crate::youtube::VideoMetadata;
+use crate::Platform;
 
-async fn upload_large_video(
-    max_file_size: u64, bot: &Bot, chat_id: i64, video_path: &str) -> Result<()> {
+const MAX_THUMBNAIL_SIZE: u64 = 200 * 1024;
+// Longest caption and message Telegram accepts, counted without markup
+const MAX_CAPTION: usize = 1024;
+const MAX_MESSAGE: usize = 4096;
 
-    let file_size = metadata(video_path)
-        .map(|m| m.len())
-        .context("Failed to get file metadata")?;
+fn file_len(path: &str) -> u64 {
+    metadata(path).map(|m| m.len()).unwrap_or(u64::MAX)
+}
 
-    let mut start = 0;
-    let mut end = max_file_size;
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video.mp4".to_string())
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct TelegramConfig {
+    // Chats the video is posted to. The first one receives the upload, the others a copy by
+    // `file_id`. Without any, the video goes to `--chat-id`.
+    pub destinations: Vec<DestinationConfig>,
+    // How captions and messages are formatted unless a destination says otherwise
+    pub parse_mode: Markup,
+    // Rows of link buttons under the video
+    pub buttons: Vec<Vec<ButtonConfig>>,
+}
 
-    let file = File::open(video_path).await.context("Failed to open file")?;
-    let mut reader = BufReader::new(file);
+// Both fields are templates. A button whose URL renders empty, like `{vk_url}` before the VK upload
+// has finished, is left out until the buttons are updated.
+#[derive(Deserialize, Clone)]
+pub(crate) struct ButtonConfig {
+    pub text: String,
+    pub url: String,
+}
 
-    let mut chunks: Vec<Vec<u8>> = Vec::new();
+#[derive(Deserialize, Clone)]
+pub(crate) struct DestinationConfig {
+    pub chat_id: i64,
+    // Topic of a forum group
+    pub message_thread_id: Option<i32>,
+    // Caption and messages for this chat instead of the Telegram template
+    pub template: Option<TemplateConfig>,
+    pub parse_mode: Option<Markup>,
+    // Buttons for this chat instead of the Telegram ones
+    pub buttons: Option<Vec<Vec<ButtonConfig>>>,
+    #[serde(default)]
+    pub disable_notification: bool,
+    #[serde(default)]
+    pub protect_content: bool,
+}
 
-    // Loop through the file in chunks
-    while start < file_size {
-        if end > file_size {
-            end = file_size;
+impl DestinationConfig {
+    fn new(chat_id: i64) -> DestinationConfig {
+        DestinationConfig {
+            chat_id,
+            message_thread_id: None,
+            template: None,
+            parse_mode: None,
+            buttons: None,
+            disable_notification: false,
+            protect_content: false,
+        }
+    }
+}
+
+pub(crate) fn parse_mode(markup: Markup) -> Option<ParseMode> {
+    match markup {
+        Markup::Plain => None,
+        Markup::Html => Some(ParseMode::Html),
+        Markup::MarkdownV2 => Some(ParseMode::MarkdownV2),
+    }
+}
+
+// Cuts `text` at the last line break that shows at most `max` characters and leaves the markup
+// intact. Returns the part that fits, the rest and how both are formatted; a text without such a
+// line break is cut as plain text. Telegram counts characters in UTF-16 code units, so an emoji
+// outside the basic plane counts twice.
+fn split(text: &str, markup: Markup, max: usize) -> (String, String, Markup) {
+    let fits = |part: &str| markup.strip(part).encode_utf16().count() <= max;
+    if fits(text) {
+        return (text.to_string(), String::new(), markup);
+    }
+    for (i, _) in text.rmatch_indices('\n') {
+        let head = &text[..i];
+        if !head.trim().is_empty() && fits(head) && markup.is_balanced(head) {
+            return (head.trim_end().to_string(), text[i + 1..].trim_start().to_string(), markup);
+        }
+    }
+    let plain = markup.strip(text);
+    let mut units = 0;
+    let mut cut = plain.char_indices()
+        .find(|(_, c)| {
+            units += c.len_utf16();
+            units > max
+        })
+        .map_or(plain.len(), |(i, _)| i);
+    // Rather not through a word
+    if let Some(space) = plain[..cut].rfind(char::is_whitespace).filter(|space| *space > cut / 2) {
+        cut = space;
+    }
+    (plain[..cut].trim_end().to_string(), plain[cut..].trim_start().to_string(), Markup::Plain)
+}
+
+// The whole text in messages of at most `max` characters each
+fn parts(text: &str, markup: Markup, max: usize) -> Vec<(String, Markup)> {
+    let mut parts = Vec::new();
+    let (mut rest, mut markup) = (text.to_string(), markup);
+    while !rest.trim().is_empty() {
+        let (part, next, part_markup) = split(&rest, markup, max);
+        parts.push((part, part_markup));
+        (rest, markup) = (next, part_markup);
+    }
+    parts
+}
+
+fn keyboard(rows: &[Vec<ButtonConfig>], vars: &HashMap<String, String>) -> Result<Option<InlineKeyboardMarkup>> {
+    let mut keyboard = Vec::new();
+    for row in rows {
+        let mut buttons = Vec::new();
+        for button in row {
+            let url = template::render(&button.url, vars, None, Markup::Plain)?;
+            if url.trim().is_empty() {
+                continue;
+            }
+            let url = Url::parse(url.trim()).context(format!("Invalid button URL: {}", url))?;
+            buttons.push(InlineKeyboardButton::url(template::render(&button.text, vars, None, Markup::Plain)?, url));
+        }
+        if !buttons.is_empty() {
+            keyboard.push(buttons);
+        }
+    }
+    Ok((!keyboard.is_empty()).then(|| InlineKeyboardMarkup::new(keyboard)))
+}
+
+// A destination with its texts rendered for one video
+pub(crate) struct Destination {
+    pub config: DestinationConfig,
+    pub text: PostText,
+    // How `text` is formatted
+    pub markup: Markup,
+    pub keyboard: Option<InlineKeyboardMarkup>,
+}
+
+impl Destination {
+    fn new(chat_id: i64, text: PostText, markup: Markup) -> Destination {
+        Destination { config: DestinationConfig::new(chat_id), text, markup, keyboard: None }
+    }
+
+    fn message(&self, bot: &Bot, text: &str, markup: Markup) -> JsonRequest<SendMessage> {
+        let mut request = bot.send_message(ChatId(self.config.chat_id), text)
+            .disable_notification(self.config.disable_notification)
+            .protect_content(self.config.protect_content);
+        if let Some(thread) = self.config.message_thread_id {
+            request = request.message_thread_id(ThreadId(MessageId(thread)));
+        }
+        if let Some(mode) = parse_mode(markup) {
+            request = request.parse_mode(mode);
+        }
+        request
+    }
+
+    // A video message with the stream parameters of `video`, and the caption and buttons unless
+    // the video is split into parts. What does not fit into a caption is left to `send_overflow`.
+    fn video(&self, bot: &Bot, file: InputFile, video: &VideoMetadata, caption: bool) -> MultipartRequest<SendVideo> {
+        let mut request = bot.send_video(ChatId(self.config.chat_id), file)
+            .supports_streaming(true)
+            .disable_notification(self.config.disable_notification)
+            .protect_content(self.config.protect_content);
+        if let Some(thread) = self.config.message_thread_id {
+            request = request.message_thread_id(ThreadId(MessageId(thread)));
+        }
+        if caption {
+            let (caption, _, markup) = split(&self.text.description, self.markup, MAX_CAPTION);
+            request = request.caption(caption);
+            if let Some(mode) = parse_mode(markup) {
+                request = request.parse_mode(mode);
+            }
+            if let Some(keyboard) = &self.keyboard {
+                request = request.reply_markup(keyboard.clone());
+            }
+        }
+        if let Some(duration) = video.duration {
+            request = request.duration(duration.round() as u32);
+        }
+        if let (Some(width), Some(height)) = (video.width, video.height) {
+            request = request.width(width).height(height);
+        }
+        request
+    }
+
+    async fn send_text(&self, bot: &Bot, text: &str, what: &str, retry: &Retry<'_>) -> Result<()> {
+        for (text, markup) in parts(text, self.markup, MAX_MESSAGE) {
+            retry.run(what, || async { Ok(self.message(bot, &text, markup).send().await?) }).await?;
+        }
+        Ok(())
+    }
+
+    // Sends the part of the caption that did not fit under the video as replies to it
+    async fn send_overflow(&self, bot: &Bot, video: &Message, retry: &Retry<'_>) -> Result<()> {
+        let (_, rest, markup) = split(&self.text.description, self.markup, MAX_CAPTION);
+        for (text, markup) in parts(&rest, markup, MAX_MESSAGE) {
+            retry.run("Sending the rest of the caption", || async {
+                Ok(self.message(bot, &text, markup).reply_parameters(ReplyParameters::new(video.id)).send().await?)
+            }).await?;
         }
+        Ok(())
+    }
+}
+
+// The configured destinations, or `chat_id` with the Telegram settings if there are none
+fn destination_configs(config: &Config, chat_id: Option<i64>) -> Vec<DestinationConfig> {
+    if config.telegram.destinations.is_empty() {
+        chat_id.map(DestinationConfig::new).into_iter().collect()
+    } else {
+        config.telegram.destinations.clone()
+    }
+}
 
-        let mut chunk = vec![0u8; (end - start) as usize];
-        reader.seek(std::io::SeekFrom::Start(start as u64)).await.context("Failed to seek to the start position")?;
-        reader.read_exact(&mut chunk).await.context("Failed to read the chunk")?;
+fn render(config: &Config, destination: DestinationConfig, vars: &HashMap<String, String>) -> Result<Destination> {
+    let template = destination.template.clone().unwrap_or_else(|| config.template(Platform::Telegram));
+    let markup = destination.parse_mode.unwrap_or(config.telegram.parse_mode);
+    let buttons = destination.buttons.as_ref().unwrap_or(&config.telegram.buttons);
+    Ok(Destination {
+        text: template.render(Platform::Telegram, vars, markup)?,
+        markup,
+        keyboard: keyboard(buttons, vars)?,
+        config: destination,
+    })
+}
+
+// Renders the texts and buttons of every destination
+pub(crate) fn destinations(config: &Config, chat_id: Option<i64>, vars: &HashMap<String, String>) -> Result<Vec<Destination>> {
+    destination_configs(config, chat_id).into_iter()
+        .map(|destination| render(config, destination, vars))
+        .collect()
+}
+
+// Renders the buttons of the Telegram posts of a video again, now that `vars` holds the links of
+// more platforms, and appends the `links` template to their captions if given. The video is out
+// already, so a failure here is only reported.
+pub(crate) async fn update_posts(config: &Config, options: &UploadOptions, video_id: &str,
+                                 vars: &HashMap<String, String>, links: Option<&str>) {
+    if let Err(e) = try_update_posts(config, options, video_id, vars, links).await {
+        eprintln!("Failed to update the Telegram post: {:#}", e);
+    }
+}
 
-        chunks.push(chunk);
+async fn try_update_posts(config: &Config, options: &UploadOptions, video_id: &str,
+                          vars: &HashMap<String, String>, links: Option<&str>) -> Result<()> {
+    let messages: Vec<MessageRef> = Store::load(&config.store)?.publications.into_iter()
+        .filter(|p| p.video_id == video_id && p.platform == Platform::Telegram)
+        .flat_map(|p| p.messages)
+        .collect();
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let bot = bot(options)?;
+    let configs = destination_configs(config, options.chat_id);
+    for message in messages {
+        let destination = configs.iter()
+            .find(|d| d.chat_id == message.chat_id)
+            .cloned()
+            .unwrap_or_else(|| DestinationConfig::new(message.chat_id));
+        let destination = render(config, destination, vars)?;
+        let (chat_id, message_id) = (ChatId(message.chat_id), MessageId(message.message_id));
 
-        start = end;
-        end += max_file_size;
+        let result = match links {
+            Some(links) => {
+                let links = template::render(links, vars, None, destination.markup)?;
+                let caption = format!("{}\n\n{}", destination.text.description, links);
+                let (caption, rest, markup) = split(&caption, destination.markup, MAX_CAPTION);
+                // A caption continued in a reply keeps its text, the links would end up in the middle
+                if !rest.is_empty() {
+                    println!("The links do not fit into the caption in chat {}", message.chat_id);
+                    continue;
+                }
+                let mut request = bot.edit_message_caption(chat_id, message_id).caption(caption);
+                if let Some(mode) = parse_mode(markup) {
+                    request = request.parse_mode(mode);
+                }
+                // Without it the caption edit would remove the buttons
+                if let Some(keyboard) = destination.keyboard {
+                    request = request.reply_markup(keyboard);
+                }
+                request.await.map(|_| ())
+            }
+            None => match destination.keyboard {
+                Some(keyboard) => bot.edit_message_reply_markup(chat_id, message_id).reply_markup(keyboard).await.map(|_| ()),
+                None => continue,
+            },
+        };
+        match result {
+            // Nothing new for this message
+            Ok(()) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+            Err(e) => return Err(e).context(format!("Failed to edit message {} in chat {}", message.message_id, message.chat_id)),
+        }
     }
+    Ok(())
+}
+
+// Returns the messages of all chunks
+async fn upload_large_video(
+    max_file_size: u64, bot: &Bot, destination: &Destination, video: &VideoMetadata, video_path: &str,
+    retry: &Retry<'_>) -> Result<Vec<Message>> {
+
+    let file_size = metadata(video_path)
+        .map(|m| m.len())
+        .context("Failed to get file metadata")?;
+    let pb = progress::bytes(file_size, "Uploading to Telegram");
 
-    // Send the file in chunks
-    for chunk in chunks {
-        let input_file = InputFile::memory(chunk);
-        bot.send_video(ChatId(chat_id), input_file)
+    // Send the file in chunks, each one read from disk while it is sent
+    let mut messages = Vec::new();
+    let mut start = 0;
+    let mut part = 1;
+    while start < file_size {
+        let len = max_file_size.min(file_size - start);
+        let message = retry.run("Uploading a chunk", || async {
+            let reader = progress::open(video_path, start, len, &pb).await?;
+            let input_file = InputFile::read(reader).file_name(format!("{}.part{}", file_name(video_path), part));
+            Ok(destination.video(bot, input_file, video, false).await?)
+        })
             .await
             .context("Failed to upload chunk to Telegram")?;
+        messages.push(message);
+        start += len;
+        part += 1;
     }
-    Ok(())
+    pb.finish_with_message("Upload complete");
+    if messages.is_empty() {
+        bail!("Nothing to upload, the file is empty");
+    }
+    Ok(messages)
 }
 
-pub async fn upload_to_telegram(
-    bot_url: &str, max_file_size: u64, bot_token: &str, chat_id: i64, file_path: &str,
-    caption: &str, message_before: &str, message_after: &str) -> Result<()> {
+// Telegram rejects thumbnails above 200 kB, so larger covers are left to Telegram to generate
+fn thumbnail(video: &VideoMetadata, by_path: bool) -> Option<InputFile> {
+    let thumbnail = video.thumbnail_file.as_deref().filter(|t| file_len(t) <= MAX_THUMBNAIL_SIZE)?;
+    if by_path {
+        local_file(thumbnail).ok()
+    } else {
+        Some(InputFile::file(thumbnail))
+    }
+}
 
+// A `file://` reference that a local Bot API server opens by itself
+fn local_file(path: &str) -> Result<InputFile> {
+    let path = fs::canonicalize(path).context(format!("Failed to resolve path: {}", path))?;
+    let url = Url::from_file_path(&path).map_err(|_| anyhow!("Not an absolute path: {}", path.display()))?;
+    Ok(InputFile::url(url))
+}
+
+pub(crate) fn bot(options: &UploadOptions) -> Result<Bot> {
+    let bot_token = options.bot_token.as_deref()
+        .ok_or_else(|| UploadError::missing(Platform::Telegram, "Bot token for Telegram"))?;
+    // Enough for the largest file at 1 MB/s, which only matters for the 2 GB of a local server
+    let timeout = (options.max_file_size() / 1_000_000).max(240);
     let client = net::default_reqwest_settings()
-        .timeout(std::time::Duration::from_secs(240)).build().expect("Client creation failed");
+        .timeout(std::time::Duration::from_secs(timeout)).build().expect("Client creation failed");
+    Ok(Bot::with_client(bot_token, client).set_api_url(options.bot_api_url.parse()?))
+}
+
+// Makes sure a local Bot API server is up and knows the bot before any work is started
+pub(crate) async fn check_server(options: &UploadOptions) -> Result<()> {
+    if !options.local_bot_api {
+        return Ok(());
+    }
+    let me = bot(options)?.get_me().await
+        .context(format!("Local Bot API server at {} did not answer getMe", options.bot_api_url))?;
+    println!("Using local Bot API server at {} as @{} (files up to {} bytes{})",
+             options.bot_api_url, me.username(), options.max_file_size(),
+             if options.bot_api_shares_files { ", passed by path" } else { "" });
+    Ok(())
+}
+
+// Saves a file someone sent to the bot into `output` and returns its path. Through a local
+// server this works for files of any size, the cloud Bot API stops at 20 MB.
+pub(crate) async fn download_file(bot: &Bot, options: &UploadOptions, file_id: &str, file_name: &str,
+                                  output: &str) -> Result<String> {
+    let file = bot.get_file(file_id).await.context("Failed to get the file from Telegram")?;
+    fs::create_dir_all(output).context(format!("Failed to create directory: {}", output))?;
+    // The name comes from the sender, so only its last component is used
+    let file_name = Path::new(file_name).file_name().ok_or_else(|| anyhow!("Invalid file name: {}", file_name))?;
+    let destination = Path::new(output).join(file_name).to_string_lossy().to_string();
 
-    let bot = Bot::with_client(bot_token, client).set_api_url(bot_url.parse()?);
+    // A local server does not serve files for download, it reports where it keeps them
+    if options.local_bot_api {
+        if !options.bot_api_shares_files || !Path::new(&file.path).is_absolute() {
+            bail!("The local Bot API server keeps received files on its own disk; run the bot where it can read \
+                   them and pass --bot-api-shares-files");
+        }
+        fs::copy(&file.path, &destination).context(format!("Failed to copy {}", file.path))?;
+        return Ok(destination);
+    }
+
+    let pb = progress::bytes(file.size as u64, "Downloading from Telegram");
+    let mut target = tokio::fs::File::create(&destination).await
+        .context(format!("Failed to create file: {}", destination))?;
+    let mut stream = bot.download_file_stream(&file.path);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.context("Failed to download the file from Telegram")?;
+        target.write_all(&chunk).await?;
+        pb.inc(chunk.len() as u64);
+    }
+    target.flush().await?;
+    pb.finish_with_message("Download complete");
+    Ok(destination)
+}
+
+// Uploads to the first destination and copies the video to the others. Returns the link to
+// the first post if the chat has public or internal links, and the messages that carry buttons.
+pub async fn upload_to_telegram(
+    options: &UploadOptions, file_path: &str, video: &VideoMetadata, post: &Post,
+    retry: &Retry<'_>) -> Result<(Option<String>, Vec<MessageRef>)> {
 
+    let fallback;
+    let destinations = if post.telegram.is_empty() {
+        let chat_id = options.chat_id.ok_or_else(|| UploadError::missing(Platform::Telegram, "Chat ID for Telegram"))?;
+        fallback = [Destination::new(chat_id, post.text.clone(), post.markup)];
+        &fallback[..]
+    } else {
+        &post.telegram[..]
+    };
+    let first = &destinations[0];
+    let max_file_size = options.max_file_size();
+    let bot = bot(options)?;
 
     // Check the file size before deciding the upload method
     let file_size = metadata(file_path)
         .map(|m| m.len())
         .context("Failed to get file metadata")?;
 
-    if message_before != "" {
-        bot.send_message(ChatId(chat_id), message_before).send().await?;
-    }
+    first.send_text(&bot, &first.text.message_before, "Sending the message before the video", retry).await?;
 
-    if file_size > max_file_size {
+    let messages = if options.bot_api_shares_files && file_size <= max_file_size {
+        // The server reads the file itself, so there is nothing to stream
+        let message = retry.run("Uploading the video", || async {
+            let mut request = first.video(&bot, local_file(file_path)?, video, true);
+            if let Some(thumbnail) = thumbnail(video, true) {
+                request = request.thumbnail(thumbnail);
+            }
+            Ok(request.send().await?)
+        })
+            .await
+            .context("Failed to upload video to Telegram")?;
+        vec![message]
+    } else if file_size > max_file_size {
         // If the file is too large, use chunking
-        upload_large_video(max_file_size, &bot, chat_id, file_path).await
-            .context("Failed to upload video in chunks")?;
+        upload_large_video(max_file_size, &bot, first, video, file_path, retry).await
+            .context("Failed to upload video in chunks")?
     } else {
         // Directly send the video if the file size is within the limit
-        let input_file = InputFile::file(file_path);
-        // Make sure to call .send() and then await the result
-        bot.send_video(ChatId(chat_id), input_file)
-            .caption(caption)
-            .send()  // Send the request asynchronously
+        let pb = progress::bytes(file_size, "Uploading to Telegram");
+        let message = retry.run("Uploading the video", || async {
+            let reader = progress::open(file_path, 0, file_size, &pb).await?;
+            let input_file = InputFile::read(reader).file_name(file_name(file_path));
+            let mut request = first.video(&bot, input_file, video, true);
+            if let Some(thumbnail) = thumbnail(video, false) {
+                request = request.thumbnail(thumbnail);
+            }
+            // Make sure to call .send() and then await the result
+            Ok(request
+                .send()  // Send the request asynchronously
+                .await?)
+        })
             .await
             .context("Failed to upload video to Telegram")?;
+        pb.finish_with_message("Upload complete");
+        vec![message]
+    };
+
+    let mut posted = Vec::new();
+    if let [message] = &messages[..] {
+        first.send_overflow(&bot, message, retry).await?;
+        posted.push(MessageRef { chat_id: message.chat.id.0, message_id: message.id.0 });
     }
+    first.send_text(&bot, &first.text.message_after, "Sending the message after the video", retry).await?;
 
-    if message_after != "" {
-        bot.send_message(ChatId(chat_id), message_after).send().await?;
+    // The other chats get the uploaded file by its ID, so the bytes are sent only once
+    let file_ids: Vec<String> = messages.iter()
+        .filter_map(|message| message.video().map(|v| v.file.id.clone()))
+        .collect();
+    for destination in &destinations[1..] {
+        destination.send_text(&bot, &destination.text.message_before, "Sending the message before the video", retry).await?;
+        for file_id in &file_ids {
+            let message = retry.run("Copying the video", || async {
+                let input_file = InputFile::file_id(file_id.clone());
+                Ok(destination.video(&bot, input_file, video, file_ids.len() == 1).send().await?)
+            })
+                .await
+                .context(format!("Failed to copy video to Telegram chat {}", destination.config.chat_id))?;
+            if file_ids.len() == 1 {
+                destination.send_overflow(&bot, &message, retry).await?;
+                posted.push(MessageRef { chat_id: message.chat.id.0, message_id: message.id.0 });
+            }
+        }
+        destination.send_text(&bot, &destination.text.message_after, "Sending the message after the video", retry).await?;
     }
 
     println!("Video uploaded successfully!");
 
-    Ok(())
-}
\ No newline at end of file
+    Ok((messages[0].url().map(|url| url.to_string()), posted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(count: usize, width: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{:0width$}", i, width = width)).collect()
+    }
+
+    #[test]
+    fn split_keeps_a_text_that_fits() {
+        let text = "x".repeat(1024);
+        assert_eq!(split(&text, Markup::Plain, 1024), (text, String::new(), Markup::Plain));
+        // Markup does not count against the limit
+        let text = format!("<b>{}</b>", "x".repeat(1024));
+        assert_eq!(split(&text, Markup::Html, 1024), (text, String::new(), Markup::Html));
+    }
+
+    #[test]
+    fn split_at_the_last_line_break_that_fits() {
+        // 50 lines of 100 characters; 40 of them with their line breaks take 4039
+        let lines = numbered(50, 100);
+        let (head, rest, markup) = split(&lines.join("\n"), Markup::MarkdownV2, 4096);
+        assert_eq!(head, lines[..40].join("\n"));
+        assert_eq!(rest, lines[40..].join("\n"));
+        assert_eq!(markup, Markup::MarkdownV2);
+
+        let lines = numbered(11, 100);
+        let (head, rest, _) = split(&lines.join("\n"), Markup::Plain, 1024);
+        assert_eq!(head.chars().count(), 1009);
+        assert_eq!(rest, lines[10]);
+    }
+
+    #[test]
+    fn split_skips_line_breaks_inside_markup() {
+        let text = format!("<b>{}\n{}</b>\n{}", "a".repeat(500), "b".repeat(500), "c".repeat(500));
+        let (head, rest, markup) = split(&text, Markup::Html, 1024);
+        assert_eq!(head, format!("<b>{}\n{}</b>", "a".repeat(500), "b".repeat(500)));
+        assert_eq!(rest, "c".repeat(500));
+        assert_eq!(markup, Markup::Html);
+    }
+
+    #[test]
+    fn split_without_a_usable_line_break_falls_back_to_plain_text() {
+        // The only line break is inside the bold text, so it is cut as plain text at the last space
+        let text = format!("<b>{} {}\n{}</b>", "a".repeat(1000), "b".repeat(10), "c".repeat(100));
+        let (head, rest, markup) = split(&text, Markup::Html, 1024);
+        assert_eq!(head, format!("{} {}", "a".repeat(1000), "b".repeat(10)));
+        assert_eq!(rest, "c".repeat(100));
+        assert_eq!(markup, Markup::Plain);
+
+        // A single word is cut at the limit
+        let text = "x".repeat(5000);
+        let (head, rest, _) = split(&text, Markup::Plain, 4096);
+        assert_eq!(head.chars().count(), 4096);
+        assert_eq!(rest.chars().count(), 904);
+    }
+
+    #[test]
+    fn parts_cover_the_whole_text() {
+        // A caption of 1024 characters and then messages of up to 4096
+        let lines = numbered(100, 99);
+        let text = lines.join("\n");
+        let (caption, rest, markup) = split(&text, Markup::Plain, 1024);
+        let messages = parts(&rest, markup, 4096);
+        assert_eq!(caption, lines[..10].join("\n"));
+        assert_eq!(messages.iter().map(|(part, _)| part.chars().count()).collect::<Vec<_>>(), vec![3999, 3999, 999]);
+        let sent: Vec<&str> = std::iter::once(caption.as_str()).chain(messages.iter().map(|(part, _)| part.as_str())).collect();
+        assert_eq!(sent.join("\n"), text);
+
+        assert!(parts("", Markup::Html, 4096).is_empty());
+        assert_eq!(parts("short", Markup::Html, 4096), vec![("short".to_string(), Markup::Html)]);
+    }
+
+    #[test]
+    fn parts_continue_as_plain_text_after_a_plain_cut() {
+        let text = format!("<b>{}</b>", "word ".repeat(2000));
+        let messages = parts(&text, Markup::Html, 4096);
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|(part, markup)| *markup == Markup::Plain && part.chars().count() <= 4096));
+        assert_eq!(messages.iter().map(|(part, _)| part.as_str()).collect::<Vec<_>>().join(" ").trim_end(),
+                   "word ".repeat(2000).trim_end());
+    }
+
+    #[test]
+    fn split_counts_characters_not_bytes() {
+        let text = "я".repeat(1500);
+        let (head, rest, _) = split(&text, Markup::Plain, 1024);
+        assert_eq!(head.chars().count(), 1024);
+        assert_eq!(rest.chars().count(), 476);
+    }
+
+    #[test]
+    fn split_counts_utf16_code_units() {
+        // Each of these emoji takes two UTF-16 code units
+        let text = "😀".repeat(512);
+        assert_eq!(split(&text, Markup::Plain, 1024), (text.clone(), String::new(), Markup::Plain));
+        let text = format!("{}x", text);
+        let (head, rest, _) = split(&text, Markup::Plain, 1024);
+        assert_eq!(head, "😀".repeat(512));
+        assert_eq!(rest, "x");
+
+        // An emoji that would straddle the limit moves to the rest as a whole
+        let text = format!("x{}", "😀".repeat(512));
+        let (head, rest, _) = split(&text, Markup::Plain, 1024);
+        assert_eq!(head, format!("x{}", "😀".repeat(511)));
+        assert_eq!(rest, "😀");
+
+        // A line of 512 emoji fits, one more character on it does not
+        let text = format!("{}\n{}x", "😀".repeat(512), "😀".repeat(512));
+        let (head, rest, _) = split(&text, Markup::Plain, 1024);
+        assert_eq!(head, "😀".repeat(512));
+        assert_eq!(rest, format!("{}x", "😀".repeat(512)));
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use crate::youtube::VideoMetadata;
+use crate::Platform;
+
+// Text templates of one platform. Telegram has no separate title, its caption is `description`.
+//
+// Syntax:
+//   {title}                        variable
+//   {description|fit}              filter; `fit` shortens the value so the whole text stays within the
+//                                  platform's length limit, `truncate:N` cuts to N characters, `upper`
+//                                  and `lower` change the case, `bold` and `link:TEXT` format the value
+//                                  when the text is sent as HTML or MarkdownV2
+//   {#if uploader}by {uploader}{#else}...{/if}
+//                                  rendered only if the variable is not empty
+//   {{ and }}                      literal braces
+//
+// With HTML or MarkdownV2 the values of variables are escaped, the template itself is not, so it can
+// hold markup of its own.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct TemplateConfig {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub message_before: Option<String>,
+    pub message_after: Option<String>,
+    pub max_title: Option<usize>,
+    pub max_description: Option<usize>,
+}
+
+// Formatting of a rendered text; Telegram is the only platform that understands markup
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Markup {
+    #[default]
+    Plain,
+    Html,
+    MarkdownV2,
+}
+
+impl Markup {
+    pub(crate) fn escape(self, text: &str) -> String {
+        match self {
+            Markup::Plain => text.to_string(),
+            Markup::Html => text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+            Markup::MarkdownV2 => {
+                let mut out = String::with_capacity(text.len());
+                for c in text.chars() {
+                    if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                out
+            }
+        }
+    }
+
+    fn bold(self, text: &str) -> String {
+        match self {
+            Markup::Plain => text.to_string(),
+            Markup::Html => format!("<b>{}</b>", self.escape(text)),
+            Markup::MarkdownV2 => format!("*{}*", self.escape(text)),
+        }
+    }
+
+    // The text as the reader sees it
+    pub(crate) fn strip(self, text: &str) -> String {
+        match self {
+            Markup::Plain => text.to_string(),
+            Markup::Html => {
+                let mut out = String::with_capacity(text.len());
+                let mut rest = text;
+                while let Some(c) = rest.chars().next() {
+                    if c == '<' {
+                        rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+                    } else if let Some((entity, c)) = [("&amp;", '&'), ("&lt;", '<'), ("&gt;", '>'), ("&quot;", '"')]
+                        .into_iter()
+                        .find(|(entity, _)| rest.starts_with(entity)) {
+                        out.push(c);
+                        rest = &rest[entity.len()..];
+                    } else {
+                        out.push(c);
+                        rest = &rest[c.len_utf8()..];
+                    }
+                }
+                out
+            }
+            Markup::MarkdownV2 => {
+                let mut out = String::with_capacity(text.len());
+                let mut chars = text.chars().peekable();
+                let mut in_url = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(c) = chars.next().filter(|_| !in_url) {
+                                out.push(c);
+                            }
+                        }
+                        ']' if chars.peek() == Some(&'(') => {
+                            chars.next();
+                            in_url = true;
+                        }
+                        ')' if in_url => in_url = false,
+                        _ if in_url => {}
+                        '*' | '_' | '~' | '|' | '`' | '[' => {}
+                        c => out.push(c),
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    // Whether every tag or entity opened in `text` is also closed in it
+    pub(crate) fn is_balanced(self, text: &str) -> bool {
+        match self {
+            Markup::Plain => true,
+            Markup::Html => {
+                let mut depth = 0i32;
+                let mut rest = text;
+                while let Some(start) = rest.find('<') {
+                    let Some(end) = rest[start..].find('>') else { return false };
+                    depth += if rest[start + 1..].starts_with('/') { -1 } else { 1 };
+                    rest = &rest[start + end + 1..];
+                }
+                depth == 0
+            }
+            Markup::MarkdownV2 => {
+                let (mut brackets, mut parens) = (0, 0);
+                let mut open = HashMap::new();
+                let mut chars = text.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '[' => brackets += 1,
+                        ']' => brackets -= 1,
+                        '(' => parens += 1,
+                        ')' => parens -= 1,
+                        '*' | '_' | '~' | '|' | '`' => *open.entry(c).or_insert(false) ^= true,
+                        _ => {}
+                    }
+                }
+                brackets == 0 && parens == 0 && !open.values().any(|open| *open)
+            }
+        }
+    }
+
+    // Without markup the bare URL is all a reader can click
+    fn link(self, url: &str, text: &str) -> String {
+        match self {
+            _ if url.is_empty() => String::new(),
+            Markup::Plain => url.to_string(),
+            Markup::Html => format!("<a href=\"{}\">{}</a>", self.escape(url).replace('"', "&quot;"), self.escape(text)),
+            Markup::MarkdownV2 => format!("[{}]({})", self.escape(text), url.replace('\\', "\\\\").replace(')', "\\)")),
+        }
+    }
+}
+
+// The texts sent along with a video to one platform
+#[derive(Clone, Default)]
+pub(crate) struct PostText {
+    pub title: String,
+    pub description: String,
+    pub message_before: String,
+    pub message_after: String,
+}
+
+impl TemplateConfig {
+    // Without configuration every platform publishes what it always did
+    pub(crate) fn default_for(platform: Platform) -> TemplateConfig {
+        let (title, description, message_before) = match platform {
+            Platform::Telegram => ("{title}", "{title|bold}", "{source_url|link}"),
+            Platform::Discord | Platform::Mastodon | Platform::Matrix => ("{title}", "{title}\n{source_url}", ""),
+            _ => ("{title}", "{description|fit}", ""),
+        };
+        TemplateConfig {
+            title: Some(title.to_string()),
+            description: Some(description.to_string()),
+            message_before: Some(message_before.to_string()),
+            message_after: Some(String::new()),
+            max_title: None,
+            max_description: None,
+        }
+    }
+
+    pub(crate) fn render(&self, platform: Platform, vars: &HashMap<String, String>, markup: Markup) -> Result<PostText> {
+        let defaults = TemplateConfig::default_for(platform);
+        let (title_limit, description_limit) = limits(platform);
+        let pick = |own: &Option<String>, default: &Option<String>| own.clone().or(default.clone()).unwrap_or_default();
+
+        Ok(PostText {
+            title: render(&pick(&self.title, &defaults.title), vars,
+                          Some(self.max_title.unwrap_or(title_limit)), markup)?,
+            description: render(&pick(&self.description, &defaults.description), vars,
+                                Some(self.max_description.unwrap_or(description_limit)), markup)?,
+            message_before: render(&pick(&self.message_before, &defaults.message_before), vars, Some(4096), markup)?,
+            message_after: render(&pick(&self.message_after, &defaults.message_after), vars, Some(4096), markup)?,
+        })
+    }
+}
+
+// Title and description limits in characters. A Telegram caption above 1024 characters continues
+// in a message of up to 4096 that replies to the video.
+pub(crate) fn limits(platform: Platform) -> (usize, usize) {
+    match platform {
+        Platform::Telegram => (1024, 1024 + 4096),
+        Platform::Vk => (128, 5000),
+        Platform::Rutube => (100, 5000),
+        Platform::Peertube => (120, 10000),
+        Platform::Ok => (255, 5000),
+        Platform::Youtube => (100, 5000),
+        // Discord's embed title and message, Mastodon's default status length
+        Platform::Discord => (256, 2000),
+        Platform::Mastodon => (100, 500),
+        Platform::Matrix => (255, 30000),
+        // Texts are not stored with the file
+        Platform::S3 | Platform::Folder | Platform::Webdav => (1024, 1024),
+    }
+}
+
+// Variables available to templates. Links to copies on other platforms are added as `{<platform>_url}`
+// once those uploads have finished.
+pub(crate) fn variables(metadata: &VideoMetadata, source_url: &str) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    vars.insert("id".to_string(), metadata.id.clone());
+    vars.insert("title".to_string(), metadata.title.clone());
+    vars.insert("description".to_string(), metadata.description.clone());
+    vars.insert("source_url".to_string(), source_url.to_string());
+    vars.insert("uploader".to_string(),
+                metadata.uploader.clone().or(metadata.channel.clone()).unwrap_or_default());
+    vars.insert("upload_date".to_string(), metadata.upload_date.as_deref().map(format_date).unwrap_or_default());
+    vars.insert("duration".to_string(), metadata.duration.map(format_duration).unwrap_or_default());
+    vars.insert("chapters".to_string(), metadata.chapters.iter()
+        .map(|c| format!("{} {}", format_duration(c.start_time), c.title))
+        .collect::<Vec<_>>()
+        .join("\n"));
+    vars.insert("hashtags".to_string(), metadata.tags.iter()
+        .map(|t| t.chars().filter(|c| c.is_alphanumeric() || *c == '_').collect::<String>())
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("#{}", t))
+        .collect::<Vec<_>>()
+        .join(" "));
+    vars
+}
+
+// Renders a file path or object key. Values cannot add directories of their own, so slashes and
+// characters that file systems reject are replaced, and empty or `..` components are dropped.
+pub(crate) fn render_path(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let vars = vars.iter()
+        .map(|(name, value)| {
+            let value: String = value.chars()
+                .map(|c| if c.is_control() || "/\\:*?\"<>|".contains(c) { '_' } else { c })
+                .collect();
+            (name.clone(), value.trim().to_string())
+        })
+        .collect();
+    let path = render(template, &vars, None, Markup::Plain)?;
+    let path = path.split('/')
+        .map(str::trim)
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.is_empty() {
+        bail!("Template renders an empty path: {}", template);
+    }
+    Ok(path)
+}
+
+// YYYYMMDD -> YYYY-MM-DD
+fn format_date(date: &str) -> String {
+    if date.len() == 8 && date.chars().all(|c| c.is_ascii_digit()) {
+        format!("{}-{}-{}", &date[0..4], &date[4..6], &date[6..8])
+    } else {
+        date.to_string()
+    }
+}
+
+pub(crate) fn format_duration(seconds: f64) -> String {
+    let total = seconds.max(0.0).round() as u64;
+    let (hours, minutes, seconds) = (total / 3600, total % 3600 / 60, total % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+enum Node {
+    Text(String),
+    Var { name: String, filters: Vec<(String, Option<String>)> },
+    If { name: String, then: Vec<Node>, otherwise: Vec<Node> },
+}
+
+pub(crate) fn render(template: &str, vars: &HashMap<String, String>, limit: Option<usize>, markup: Markup) -> Result<String> {
+    let nodes = parse(template)?;
+
+    // Render once with all `fit` values left out to learn how much room the rest of the text takes,
+    // then hand the remaining room to the `fit` values in order of appearance
+    let mut budget = Some(0);
+    let fixed = render_nodes(&nodes, vars, &mut budget, markup);
+    let mut budget = limit.map(|limit| limit.saturating_sub(fixed.chars().count()));
+    let text = render_nodes(&nodes, vars, &mut budget, markup);
+
+    // Cutting through markup would leave broken tags; Telegram splits long texts itself
+    Ok(match limit {
+        Some(limit) if markup == Markup::Plain => truncate(&text, limit),
+        _ => text,
+    })
+}
+
+fn render_nodes(nodes: &[Node], vars: &HashMap<String, String>, budget: &mut Option<usize>, markup: Markup) -> String {
+    let mut out = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { name, filters } => {
+                let mut value = vars.get(name).cloned().unwrap_or_default();
+                // `bold` and `link` escape what they wrap
+                let mut escaped = false;
+                for (filter, arg) in filters {
+                    value = match (filter.as_str(), arg) {
+                        ("bold", _) if !escaped => {
+                            escaped = true;
+                            markup.bold(&value)
+                        }
+                        ("link", text) if !escaped => {
+                            escaped = true;
+                            markup.link(&value, text.as_deref().unwrap_or(&value))
+                        }
+                        ("upper", _) => value.to_uppercase(),
+                        ("lower", _) => value.to_lowercase(),
+                        ("truncate", Some(n)) => truncate(&value, n.parse().unwrap_or(usize::MAX)),
+                        ("fit", _) => match budget {
+                            Some(room) => {
+                                let fitted = truncate(&value, *room);
+                                *room -= fitted.chars().count();
+                                fitted
+                            }
+                            None => value,
+                        },
+                        _ => value,
+                    };
+                }
+                if !escaped {
+                    value = markup.escape(&value);
+                }
+                out.push_str(&value);
+            }
+            Node::If { name, then, otherwise } => {
+                let set = vars.get(name).is_some_and(|v| !v.trim().is_empty());
+                out.push_str(&render_nodes(if set { then } else { otherwise }, vars, budget, markup));
+            }
+        }
+    }
+    out
+}
+
+// Cuts to at most `max` characters, marking the cut with an ellipsis
+pub(crate) fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+    let mut cut: String = text.chars().take(max - 1).collect();
+    cut.push('…');
+    cut
+}
+
+fn parse(template: &str) -> Result<Vec<Node>> {
+    // Stack of open blocks: (condition, nodes of the `then` branch, nodes of the `else` branch, in else)
+    let mut stack: Vec<(String, Vec<Node>, Vec<Node>, bool)> = Vec::new();
+    let mut root = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+
+    fn current<'a>(root: &'a mut Vec<Node>, stack: &'a mut [(String, Vec<Node>, Vec<Node>, bool)]) -> &'a mut Vec<Node> {
+        match stack.last_mut() {
+            Some((_, _, otherwise, true)) => otherwise,
+            Some((_, then, _, false)) => then,
+            None => root,
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut tag = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => tag.push(c),
+                        None => bail!("Unclosed '{{' in template: {}", template),
+                    }
+                }
+                if !text.is_empty() {
+                    current(&mut root, &mut stack).push(Node::Text(std::mem::take(&mut text)));
+                }
+                let tag = tag.trim();
+                if let Some(name) = tag.strip_prefix("#if ") {
+                    stack.push((name.trim().to_string(), Vec::new(), Vec::new(), false));
+                } else if tag == "#else" {
+                    match stack.last_mut() {
+                        Some(block) => block.3 = true,
+                        None => bail!("{{#else}} without {{#if}} in template: {}", template),
+                    }
+                } else if tag == "/if" {
+                    let Some((name, then, otherwise, _)) = stack.pop() else {
+                        bail!("{{/if}} without {{#if}} in template: {}", template);
+                    };
+                    current(&mut root, &mut stack).push(Node::If { name, then, otherwise });
+                } else {
+                    let mut parts = tag.split('|').map(str::trim);
+                    let name = parts.next().unwrap_or("").to_string();
+                    let filters = parts
+                        .map(|f| match f.split_once(':') {
+                            Some((name, arg)) => (name.trim().to_string(), Some(arg.trim().to_string())),
+                            None => (f.to_string(), None),
+                        })
+                        .collect();
+                    current(&mut root, &mut stack).push(Node::Var { name, filters });
+                }
+            }
+            c => text.push(c),
+        }
+    }
+    if !stack.is_empty() {
+        bail!("Unclosed {{#if}} in template: {}", template);
+    }
+    if !text.is_empty() {
+        root.push(Node::Text(text));
+    }
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn plain(template: &str, vars: &HashMap<String, String>) -> String {
+        render(template, vars, None, Markup::Plain).unwrap()
+    }
+
+    #[test]
+    fn renders_variables_and_filters() {
+        let vars = vars(&[("title", "Hello World"), ("n", "3")]);
+        assert_eq!(plain("{title} #{n}", &vars), "Hello World #3");
+        assert_eq!(plain("{ title | upper }", &vars), "HELLO WORLD");
+        assert_eq!(plain("{title|lower}", &vars), "hello world");
+        assert_eq!(plain("{title|truncate:6}", &vars), "Hello…");
+        assert_eq!(plain("{{title}}", &vars), "{title}");
+    }
+
+    #[test]
+    fn missing_variables_render_empty() {
+        assert_eq!(plain("[{nothing}]", &vars(&[])), "[]");
+        assert_eq!(plain("[{nothing|upper|fit}]", &vars(&[])), "[]");
+    }
+
+    #[test]
+    fn conditionals() {
+        let template = "{title}{#if uploader} by {uploader}{#else} (anonymous){/if}";
+        assert_eq!(plain(template, &vars(&[("title", "T"), ("uploader", "Ann")])), "T by Ann");
+        assert_eq!(plain(template, &vars(&[("title", "T"), ("uploader", " ")])), "T (anonymous)");
+        assert_eq!(plain(template, &vars(&[("title", "T")])), "T (anonymous)");
+        assert_eq!(plain("{#if a}a{#if b}b{/if}{/if}", &vars(&[("a", "1")])), "a");
+        assert_eq!(plain("{#if a}a{#if b}b{/if}{/if}", &vars(&[("a", "1"), ("b", "1")])), "ab");
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        for template in ["{title", "{#else}", "{/if}", "{#if title}open", "{#if a}{#if b}{/if}"] {
+            assert!(parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn fit_shares_the_room_left_by_the_rest() {
+        let vars = vars(&[("title", "Hello"), ("description", "abcdefghijklmnopqrstuvwxyz")]);
+        let text = render("{title}: {description|fit}", &vars, Some(20), Markup::Plain).unwrap();
+        assert_eq!(text, "Hello: abcdefghijkl…");
+        assert_eq!(text.chars().count(), 20);
+
+        // The first value takes what it needs, the next one gets the rest
+        let text = render("{title|fit} {description|fit}", &vars, Some(12), Markup::Plain).unwrap();
+        assert_eq!(text, "Hello abcde…");
+
+        // Without a limit and with enough room nothing is cut
+        assert_eq!(plain("{description|fit}", &vars), "abcdefghijklmnopqrstuvwxyz");
+        assert_eq!(render("{title|fit}", &vars, Some(5), Markup::Plain).unwrap(), "Hello");
+        // No room at all leaves the value out
+        assert_eq!(render("{title} {description|fit}", &vars, Some(6), Markup::Plain).unwrap(), "Hello ");
+    }
+
+    #[test]
+    fn limits_cut_plain_text_only() {
+        let vars = vars(&[("title", "abcdefgh")]);
+        assert_eq!(render("{title}", &vars, Some(5), Markup::Plain).unwrap(), "abcd…");
+        assert_eq!(render("<b>{title}</b>", &vars, Some(5), Markup::Html).unwrap(), "<b>abcdefgh</b>");
+    }
+
+    #[test]
+    fn escapes_values_but_not_the_template() {
+        let vars = vars(&[("title", "1 < 2 & 3 > 2"), ("url", "https://example.com/a_(b)")]);
+        assert_eq!(render("<i>{title}</i>", &vars, None, Markup::Html).unwrap(), "<i>1 &lt; 2 &amp; 3 &gt; 2</i>");
+        assert_eq!(render("{title|bold}", &vars, None, Markup::Html).unwrap(), "<b>1 &lt; 2 &amp; 3 &gt; 2</b>");
+        assert_eq!(render("*{title}*", &vars, None, Markup::MarkdownV2).unwrap(), "*1 < 2 & 3 \\> 2*");
+        assert_eq!(render("{url|link:Watch!}", &vars, None, Markup::MarkdownV2).unwrap(),
+                   "[Watch\\!](https://example.com/a_(b\\))");
+        assert_eq!(render("{url|link:a \"b\"}", &vars, None, Markup::Html).unwrap(),
+                   "<a href=\"https://example.com/a_(b)\">a \"b\"</a>");
+        assert_eq!(render("{url|link}", &vars, None, Markup::Plain).unwrap(), "https://example.com/a_(b)");
+    }
+
+    #[test]
+    fn escape_covers_every_markdown_v2_special_character() {
+        assert_eq!(Markup::MarkdownV2.escape("_*[]()~`>#+-=|{}.!\\"),
+                   "\\_\\*\\[\\]\\(\\)\\~\\`\\>\\#\\+\\-\\=\\|\\{\\}\\.\\!\\\\");
+        assert_eq!(Markup::MarkdownV2.escape("plain text"), "plain text");
+        assert_eq!(Markup::Html.escape("<a href=\"x\">&</a>"), "&lt;a href=\"x\"&gt;&amp;&lt;/a&gt;");
+        assert_eq!(Markup::Plain.escape("<*>"), "<*>");
+    }
+
+    #[test]
+    fn strip_leaves_what_the_reader_sees() {
+        assert_eq!(Markup::Html.strip("<b>a &amp; b</b> &lt;c&gt; <a href=\"x\">link</a>"), "a & b <c> link");
+        assert_eq!(Markup::MarkdownV2.strip("*bold* \\. _it_ [text](https://x.y/a\\)b) 1\\-2"), "bold . it text 1-2");
+        assert_eq!(Markup::Plain.strip("*a* <b>"), "*a* <b>");
+        // Escaping and stripping give back the original
+        let text = "Price: 5.00 (-10%) <new> & *hot*";
+        for markup in [Markup::Html, Markup::MarkdownV2] {
+            assert_eq!(markup.strip(&markup.escape(text)), text);
+        }
+    }
+
+    #[test]
+    fn is_balanced() {
+        assert!(Markup::Html.is_balanced("<b>bold</b> and <a href=\"x\">link</a>"));
+        assert!(!Markup::Html.is_balanced("<b>bold"));
+        assert!(!Markup::Html.is_balanced("<b>bold</b"));
+        assert!(Markup::MarkdownV2.is_balanced("*bold* _it_ [text](url) \\* \\["));
+        assert!(!Markup::MarkdownV2.is_balanced("*bold"));
+        assert!(!Markup::MarkdownV2.is_balanced("[text](url"));
+        assert!(!Markup::MarkdownV2.is_balanced("[text"));
+        assert!(Markup::Plain.is_balanced("<b>*"));
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use anyhow::Result;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use crate::config::Config;
+use crate::youtube::VideoMetadata;
+use crate::Platform;
+
+// Removed from every title before the configured rules run, whatever they are
+const SHORTVIDEO_TAG: &str = " #shortvideo";
+
+// Ordered title cleanups. A rule may replace a regex, change the case and add a prefix or suffix;
+// the parts run in that order. Hashtag rules move matches from the title into the tags. The regexes
+// are compiled when the config is loaded.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct TitleConfig {
+    pub rules: Vec<TitleRule>,
+    pub hashtags: Vec<HashtagRule>,
+    // Extra rules that run after `rules` for one platform only
+    pub platforms: HashMap<Platform, Vec<TitleRule>>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub(crate) struct TitleRule {
+    // Regex, `$1` style groups can be used in `replace`
+    #[serde(deserialize_with = "optional_regex")]
+    pub find: Option<Regex>,
+    pub replace: String,
+    pub case: Option<Case>,
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Case {
+    Lower,
+    Upper,
+    // Every word starts with a capital letter
+    Title,
+    // Only the first letter is a capital letter
+    Sentence,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct HashtagRule {
+    // Regex; the first group, or the whole match without a leading '#', becomes the tag
+    #[serde(deserialize_with = "regex")]
+    pub find: Regex,
+    // Remove the match from the title
+    #[serde(default = "default_true")]
+    pub remove: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Regex, D::Error> {
+    let find = String::deserialize(deserializer)?;
+    Regex::new(&find).map_err(|e| de::Error::custom(format!("invalid title regex {}: {}", find, e)))
+}
+
+fn optional_regex<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Regex>, D::Error> {
+    regex(deserializer).map(Some)
+}
+
+impl TitleRule {
+    fn apply(&self, title: &str) -> String {
+        let mut title = title.to_string();
+        if let Some(find) = &self.find {
+            title = find.replace_all(&title, self.replace.as_str()).to_string();
+        }
+        if let Some(case) = self.case {
+            title = change_case(&title, case);
+        }
+        if let Some(prefix) = &self.prefix {
+            title = format!("{}{}", prefix, title);
+        }
+        if let Some(suffix) = &self.suffix {
+            title = format!("{}{}", title, suffix);
+        }
+        title
+    }
+}
+
+fn change_case(title: &str, case: Case) -> String {
+    match case {
+        Case::Lower => title.to_lowercase(),
+        Case::Upper => title.to_uppercase(),
+        Case::Title => title.split(' ')
+            .map(|word| capitalize(&word.to_lowercase()))
+            .collect::<Vec<_>>()
+            .join(" "),
+        Case::Sentence => capitalize(&title.to_lowercase()),
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl TitleConfig {
+    fn apply(&self, metadata: &mut VideoMetadata, platform: Platform) {
+        for rule in &self.hashtags {
+            for captures in rule.find.captures_iter(&metadata.title) {
+                let tag = captures.get(1).or(captures.get(0)).map(|m| m.as_str()).unwrap_or("");
+                let tag = tag.trim().trim_start_matches('#');
+                if !tag.is_empty() && !metadata.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                    metadata.tags.push(tag.to_string());
+                }
+            }
+            if rule.remove {
+                metadata.title = rule.find.replace_all(&metadata.title, "").to_string();
+            }
+        }
+        let platform_rules = self.platforms.get(&platform).map(Vec::as_slice).unwrap_or(&[]);
+        for rule in self.rules.iter().chain(platform_rules) {
+            metadata.title = rule.apply(&metadata.title);
+        }
+    }
+}
+
+// Applies the global rules and then those of the profile, if any, to a copy of the metadata
+pub(crate) fn rewrite(config: &Config, profile: Option<&str>, platform: Platform,
+                      metadata: &VideoMetadata) -> Result<VideoMetadata> {
+    let mut metadata = metadata.clone();
+    metadata.title = metadata.title.replace(SHORTVIDEO_TAG, "");
+    config.titles.apply(&mut metadata, platform);
+    if let Some(name) = profile {
+        config.profile(name)?.titles.apply(&mut metadata, platform);
+    }
+    // Removed words tend to leave double or trailing spaces behind
+    metadata.title = metadata.title.split_whitespace().collect::<Vec<_>>().join(" ");
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn title(config: &Config, profile: Option<&str>, platform: Platform, title: &str) -> VideoMetadata {
+        let metadata = VideoMetadata { title: title.to_string(), ..Default::default() };
+        rewrite(config, profile, platform, &metadata).unwrap()
+    }
+
+    #[test]
+    fn always_removes_shortvideo() {
+        assert_eq!(title(&Config::default(), None, Platform::Vk, "Funny cat #shortvideo").title, "Funny cat");
+        // Also next to rules of the user's own
+        let config = config(serde_json::json!({ "titles": { "rules": [{ "suffix": "!" }] } }));
+        assert_eq!(title(&config, None, Platform::Vk, "Funny cat #shortvideo").title, "Funny cat!");
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let config = config(serde_json::json!({ "titles": { "rules": [
+            { "find": "(?i)\\s*\\(official video\\)", "replace": "" },
+            { "find": "^(\\w+) - (.+)$", "replace": "$2 by $1", "case": "title", "prefix": "[Music] " },
+            { "suffix": "!" },
+        ] } }));
+        assert_eq!(title(&config, None, Platform::Vk, "artist - my SONG (Official Video)").title,
+                   "[Music] My Song By Artist!");
+    }
+
+    #[test]
+    fn changes_case() {
+        assert_eq!(change_case("hello WORLD", Case::Upper), "HELLO WORLD");
+        assert_eq!(change_case("Hello WORLD", Case::Lower), "hello world");
+        assert_eq!(change_case("hello WORLD", Case::Title), "Hello World");
+        assert_eq!(change_case("hello WORLD", Case::Sentence), "Hello world");
+        assert_eq!(change_case("", Case::Sentence), "");
+    }
+
+    #[test]
+    fn moves_hashtags_into_tags() {
+        let config = config(serde_json::json!({ "titles": { "hashtags": [
+            { "find": "#\\w+" },
+            { "find": "\\[(\\w+)\\]", "remove": false },
+        ] } }));
+        let metadata = VideoMetadata { title: "New #Cooking video #recipe [Live]".to_string(),
+                                       tags: vec!["recipe".to_string()], ..Default::default() };
+        let rewritten = rewrite(&config, None, Platform::Vk, &metadata).unwrap();
+        assert_eq!(rewritten.title, "New video [Live]");
+        assert_eq!(rewritten.tags, vec!["recipe", "Cooking", "Live"]);
+    }
+
+    #[test]
+    fn runs_platform_and_profile_rules_after_the_global_ones() {
+        let config = config(serde_json::json!({
+            "titles": { "rules": [{ "prefix": "A " }], "platforms": { "telegram": [{ "prefix": "T " }] } },
+            "profiles": { "series": { "titles": { "rules": [{ "prefix": "P " }] } } },
+        }));
+        assert_eq!(title(&config, None, Platform::Vk, "x").title, "A x");
+        assert_eq!(title(&config, None, Platform::Telegram, "x").title, "T A x");
+        assert_eq!(title(&config, Some("series"), Platform::Telegram, "x").title, "P T A x");
+        assert!(rewrite(&config, Some("missing"), Platform::Vk, &VideoMetadata::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_regexes_when_loading() {
+        for titles in [serde_json::json!({ "rules": [{ "find": "(" }] }), serde_json::json!({ "hashtags": [{ "find": "[" }] })] {
+            let error = serde_json::from_value::<Config>(serde_json::json!({ "titles": titles })).err().unwrap();
+            assert!(error.to_string().contains("invalid title regex"), "{}", error);
+        }
+    }
+}
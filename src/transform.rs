@@ -1,6 +1,8 @@
 use anyhow::{Result, Context};
 use std::process::Command;
 use std::ffi::OsStr; // Needed for Command::args
+use crate::error::{run_tool, tool_output, TransformError};
+use crate::progress;
 
 pub(crate) struct Metadata {
     pub width: i32,
@@ -11,12 +13,13 @@ pub(crate) struct Metadata {
 // Optional configuration to choose between two-pass or one-pass encoding
 pub(crate) enum EncodingPasses {
     TwoPass,
+    #[allow(dead_code)]
     OnePassCrf,
 }
 
 pub(crate) fn transform_video(file: &str, encoding_passes: EncodingPasses) -> Result<String> {
     // Use ffprobe for metadata extraction. It's more robust and cleaner than parsing ffmpeg's output.
-    let output = Command::new("ffprobe")
+    let output = tool_output(Command::new("ffprobe")
         .arg("-v")
         .arg("error") // Suppress verbose output, only show errors
         .arg("-select_streams")
@@ -25,20 +28,24 @@ pub(crate) fn transform_video(file: &str, encoding_passes: EncodingPasses) -> Re
         .arg("stream=width,height:format=duration") // Show width, height from stream, and duration from format
         .arg("-of")
         .arg("default=noprint_wrappers=1:nokey=1") // Output in a clean, key-value format (values only, no keys)
-        .arg(file)
-        .output()
+        .arg(file))
+        .map_err(TransformError::from)
         .context("Failed to run FFprobe for metadata. Is ffprobe installed and in PATH?")?;
+    if !output.status.success() {
+        return Err(TransformError::from_stderr(&String::from_utf8_lossy(&output.stderr)).into());
+    }
 
-
-    let metadata = parse_ffprobe_output(&output.stdout)?; // Adjusted parsing function
+    let metadata = parse_ffprobe_output(&output.stdout) // Adjusted parsing function
+        .map_err(|e| TransformError::InvalidInput(format!("{:#}", e)))?;
     let is_portrait = metadata.width < metadata.height;
     let duration_seconds = metadata.duration;
 
     let output_file = format!("{}_compressed.mp4", file);
+    let pb = progress::spinner(format!("Transforming {}", file));
 
     // Retain your existing logic for bitrate and scaling based on video duration
     let target_size_mb: f64 = 300.0; // Maximum target size in MB for bitrate calculation
-    let avg_bitrate = ((target_size_mb * 8_000.0) / duration_seconds).min(2500.0).max(500.0) as i32;
+    let avg_bitrate = ((target_size_mb * 8_000.0) / duration_seconds).clamp(500.0, 2500.0) as i32;
     let max_bitrate = (avg_bitrate as f64 * 1.5) as i32;
 
     // Determine preset, CRF value, and scaling based on duration
@@ -69,7 +76,7 @@ pub(crate) fn transform_video(file: &str, encoding_passes: EncodingPasses) -> Re
     let common_output_args: Vec<String> = vec![
         "-movflags".into(), "+faststart".into(), // Places metadata at the beginning for faster playback and preview generation
         "-y".into(), // Overwrite output file without asking
-        output_file.clone().into(), // Clone output_file String
+        output_file.clone(), // Clone output_file String
     ];
 
     match encoding_passes {
@@ -93,11 +100,7 @@ pub(crate) fn transform_video(file: &str, encoding_passes: EncodingPasses) -> Re
                 "/dev/null".into(), // Output to null device (discard video output)
             ]);
 
-            // Command::args takes an iterator of AsRef<OsStr>, which &String implements
-            Command::new("ffmpeg")
-                .args(pass1_args.iter().map(|s| s.as_ref() as &OsStr))
-                .status()
-                .context("Failed in first pass of FFmpeg encoding")?;
+            run_ffmpeg(&pass1_args).context("Failed in first pass of FFmpeg encoding")?;
 
             // **Second Pass: Encoding (with iOS compatibility parameters)**
             let mut pass2_args: Vec<String> = vec![
@@ -117,11 +120,7 @@ pub(crate) fn transform_video(file: &str, encoding_passes: EncodingPasses) -> Re
             pass2_args.extend_from_slice(&common_audio_args);
             pass2_args.extend_from_slice(&common_output_args);
 
-            // Command::args takes an iterator of AsRef<OsStr), which &String implements
-            Command::new("ffmpeg")
-                .args(pass2_args.iter().map(|s| s.as_ref() as &OsStr))
-                .status()
-                .context("Failed in second pass of FFmpeg encoding")?;
+            run_ffmpeg(&pass2_args).context("Failed in second pass of FFmpeg encoding")?;
         },
         EncodingPasses::OnePassCrf => {
             // **Single Pass with CRF (simpler, but less precise control over file size)**
@@ -135,17 +134,23 @@ pub(crate) fn transform_video(file: &str, encoding_passes: EncodingPasses) -> Re
             onepass_args.extend_from_slice(&common_audio_args);
             onepass_args.extend_from_slice(&common_output_args);
 
-            // Command::args takes an iterator of AsRef<OsStr), which &String implements
-            Command::new("ffmpeg")
-                .args(onepass_args.iter().map(|s| s.as_ref() as &OsStr))
-                .status()
-                .context("Failed in one-pass FFmpeg encoding with CRF")?;
+            run_ffmpeg(&onepass_args).context("Failed in one-pass FFmpeg encoding with CRF")?;
         }
     }
 
+    pb.finish_with_message("Transform complete");
     Ok(output_file)
 }
 
+fn run_ffmpeg(args: &[String]) -> Result<(), TransformError> {
+    // Command::args takes an iterator of AsRef<OsStr>, which &String implements
+    let (status, stderr) = run_tool(Command::new("ffmpeg").args(args.iter().map(|s| s.as_ref() as &OsStr)))?;
+    if !status.success() {
+        return Err(TransformError::from_stderr(&stderr));
+    }
+    Ok(())
+}
+
 // Adjusted parsing function for ffprobe output
 pub(crate) fn parse_ffprobe_output(output: &[u8]) -> Result<Metadata> {
     let stdout = String::from_utf8_lossy(output);
@@ -1,33 +1,194 @@
 use anyhow::{Context, Result};
+use crate::error::UploadError;
+use crate::config::Config;
+use crate::archive::{upload_to_folder, upload_to_webdav, FolderConfig, WebdavConfig};
+use crate::discord::{upload_to_discord, DiscordConfig};
+use crate::mastodon::{upload_to_mastodon, MastodonConfig};
+use crate::matrix::{upload_to_matrix, MatrixConfig};
+use crate::ok::{upload_to_ok, OkConfig};
+use crate::peertube::{upload_to_peertube, PeertubeConfig};
+use crate::retry::Retry;
+use crate::store::MessageRef;
 use crate::rutube::upload_to_rutube;
-use crate::telegram::upload_to_telegram;
+use crate::s3::{upload_to_s3, S3Config};
+use crate::telegram::{upload_to_telegram, Destination};
+use crate::template::{Markup, PostText};
 use crate::vk::upload_to_vk;
-use crate::Platform;
+use crate::youtube::VideoMetadata;
+use crate::youtube_api::{upload_to_youtube, YoutubeConfig};
+use crate::{Platform, UploadArgs};
 
-pub(crate) async fn upload(platform: Platform, file: &str, title: &str, rutube_api_key: Option<String>,
-                           bot_api_url: &str, max_file_size: u64, bot_token: Option<String>,
-                           chat_id: Option<i64>, vk_access_token: Option<String>,
-                           message_before: &str, message_after: &str) -> Result<()> {
-    match platform {
+// Upload limits of the cloud Bot API and of a local `telegram-bot-api --local`
+const CLOUD_MAX_FILE_SIZE: u64 = 50_000_000;
+const LOCAL_MAX_FILE_SIZE: u64 = 2_000_000_000;
+
+// Credentials and limits shared by all uploaders
+#[derive(Clone)]
+pub(crate) struct UploadOptions {
+    pub rutube_api_key: Option<String>,
+    pub bot_api_url: String,
+    pub max_file_size: Option<u64>,
+    // The Bot API server is a local one, with its higher limits
+    pub local_bot_api: bool,
+    // The local Bot API server can read our files by path
+    pub bot_api_shares_files: bool,
+    pub bot_token: Option<String>,
+    pub chat_id: Option<i64>,
+    pub vk_access_token: Option<String>,
+    // Instance and account, from the config file
+    pub peertube: Option<PeertubeConfig>,
+    pub ok: Option<OkConfig>,
+    pub s3: Option<S3Config>,
+    pub folder: Option<FolderConfig>,
+    pub webdav: Option<WebdavConfig>,
+    pub youtube: Option<YoutubeConfig>,
+    pub discord: Option<DiscordConfig>,
+    pub mastodon: Option<MastodonConfig>,
+    pub matrix: Option<MatrixConfig>,
+}
+
+impl UploadOptions {
+    // Command-line credentials with the platform settings of the config
+    pub(crate) fn new(args: &UploadArgs, config: &Config) -> UploadOptions {
+        UploadOptions {
+            rutube_api_key: args.rutube_api_key.clone(),
+            bot_api_url: args.bot_api_url.clone(),
+            max_file_size: args.max_file_size,
+            local_bot_api: args.local_bot_api,
+            bot_api_shares_files: args.bot_api_shares_files,
+            bot_token: args.bot_token.clone(),
+            chat_id: args.chat_id,
+            vk_access_token: args.vk_access_token.clone(),
+            peertube: config.peertube.clone(),
+            ok: config.ok.clone(),
+            s3: config.s3.clone(),
+            folder: config.folder.clone(),
+            webdav: config.webdav.clone(),
+            youtube: config.youtube.clone(),
+            discord: config.discord.clone(),
+            mastodon: config.mastodon.clone(),
+            matrix: config.matrix.clone(),
+        }
+    }
+
+    // Files above this size are split into several messages
+    pub(crate) fn max_file_size(&self) -> u64 {
+        match (self.max_file_size, self.local_bot_api) {
+            (Some(size), _) => size,
+            (None, true) => LOCAL_MAX_FILE_SIZE,
+            (None, false) => CLOUD_MAX_FILE_SIZE,
+        }
+    }
+}
+
+// What is posted along with the video
+pub(crate) struct Post {
+    pub text: PostText,
+    // How `text` is formatted; only Telegram renders anything but plain text
+    pub markup: Markup,
+    // Telegram chats with texts of their own; empty means `--chat-id` with `text`
+    pub telegram: Vec<Destination>,
+    // Unix timestamp in seconds for platforms that publish at a set time by themselves
+    pub publish_at: Option<i64>,
+}
+
+// Outcome of a successful upload
+pub(crate) struct Published {
+    pub platform: Platform,
+    // Public link to the video, if the platform reports one
+    pub url: Option<String>,
+    // Telegram messages whose buttons may be updated later
+    pub messages: Vec<MessageRef>,
+}
+
+pub(crate) async fn upload(platform: Platform, file: &str, metadata: &VideoMetadata, post: &Post,
+                           options: &UploadOptions, retry: &Retry<'_>) -> Result<Published> {
+    let (url, messages) = match upload_to(platform, file, metadata, post, options, retry).await {
+        Ok(uploaded) => uploaded,
+        // Sorted by kind so callers can tell a bad token from a flaky network
+        Err(e) => return Err(UploadError::classify(platform, &e).into()),
+    };
+    if let Some(url) = &url {
+        println!("Published to {}: {}", platform, url);
+    }
+    Ok(Published { platform, url, messages })
+}
+
+async fn upload_to(platform: Platform, file: &str, metadata: &VideoMetadata, post: &Post,
+                   options: &UploadOptions, retry: &Retry<'_>) -> Result<(Option<String>, Vec<MessageRef>)> {
+    let url = match platform {
         Platform::Rutube => {
-            let key = rutube_api_key.ok_or_else(|| anyhow::anyhow!("API key for Rutube is missing"))?;
+            let key = options.rutube_api_key.as_deref()
+                .ok_or_else(|| UploadError::missing(platform, "API key for Rutube"))?;
             println!("Uploading '{}' to Rutube", file);
-            upload_to_rutube(&key, file, title).await?;
+            upload_to_rutube(key, file, metadata, &post.text, retry).await?
         }
         Platform::Telegram => {
-            let token = bot_token.ok_or_else(|| anyhow::anyhow!("Bot token for Telegram is missing"))?;
-            let id = chat_id.ok_or_else(|| anyhow::anyhow!("Chat ID for Telegram is missing"))?;
             println!("Uploading '{}' to Telegram", file);
-            upload_to_telegram(
-                bot_api_url, max_file_size, &token, id, file, title, message_before, message_after)
+            return upload_to_telegram(options, file, metadata, post, retry)
                 .await
-                .context("Failed to upload video to Telegram")?;
+                .context("Failed to upload video to Telegram");
         }
         Platform::Vk => {
-            let token = vk_access_token.ok_or_else(|| anyhow::anyhow!("VK access token is missing"))?;
+            let token = options.vk_access_token.as_deref()
+                .ok_or_else(|| UploadError::missing(platform, "VK access token"))?;
             println!("Uploading '{}' to VK", file);
-            upload_to_vk(&token, title, file).await?;
+            upload_to_vk(token, &post.text, file, retry).await?
         }
-    }
-    Ok(())
+        Platform::Peertube => {
+            let config = options.peertube.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "PeerTube section of the config"))?;
+            println!("Uploading '{}' to PeerTube", file);
+            upload_to_peertube(config, file, metadata, &post.text, retry).await?
+        }
+        Platform::Ok => {
+            let config = options.ok.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "OK section of the config"))?;
+            println!("Uploading '{}' to OK", file);
+            upload_to_ok(config, file, metadata, &post.text, retry).await?
+        }
+        Platform::S3 => {
+            let config = options.s3.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "S3 section of the config"))?;
+            println!("Uploading '{}' to S3", file);
+            upload_to_s3(config, file, metadata, retry).await?
+        }
+        Platform::Folder => {
+            let config = options.folder.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "Folder section of the config"))?;
+            println!("Copying '{}' to {}", file, config.path);
+            upload_to_folder(config, file, metadata).await?
+        }
+        Platform::Webdav => {
+            let config = options.webdav.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "WebDAV section of the config"))?;
+            println!("Uploading '{}' to WebDAV", file);
+            upload_to_webdav(config, file, metadata, retry).await?
+        }
+        Platform::Youtube => {
+            let config = options.youtube.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "YouTube section of the config"))?;
+            println!("Uploading '{}' to YouTube", file);
+            upload_to_youtube(config, file, metadata, &post.text, post.publish_at, retry).await?
+        }
+        Platform::Discord => {
+            let config = options.discord.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "Discord section of the config"))?;
+            println!("Posting '{}' to Discord", file);
+            upload_to_discord(config, file, metadata, &post.text, retry).await?
+        }
+        Platform::Mastodon => {
+            let config = options.mastodon.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "Mastodon section of the config"))?;
+            println!("Posting '{}' to Mastodon", file);
+            upload_to_mastodon(config, file, metadata, &post.text, retry).await?
+        }
+        Platform::Matrix => {
+            let config = options.matrix.as_ref()
+                .ok_or_else(|| UploadError::missing(platform, "Matrix section of the config"))?;
+            println!("Posting '{}' to Matrix", file);
+            upload_to_matrix(config, &metadata.id, &post.text, post.markup, retry).await?
+        }
+    };
+    Ok((url, Vec::new()))
 }
@@ -1,46 +1,197 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use anyhow::{Result, anyhow};
-use reqwest::{Client, multipart::Form};
+use anyhow::{Context, Result, anyhow};
+use reqwest::{Client, StatusCode};
+use crate::error::UploadError;
+use crate::progress;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
 
-pub async fn upload_to_vk(access_token: &str, title: &str, file_path: &str) -> Result<()> {
-    let client = Client::new();
+// Size of the parts the file is sent in; a broken connection costs at most one of them
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+// An error reported in the body of a VK API response
+#[derive(Debug)]
+pub(crate) struct VkError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for VkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VK API error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for VkError {}
+
+fn check_api_error(res: &Value) -> Result<()> {
+    match res.get("error") {
+        Some(error) if error.is_object() => Err(VkError {
+            code: error["error_code"].as_i64().unwrap_or(0),
+            message: error["error_msg"].as_str().unwrap_or_default().to_string(),
+        }.into()),
+        // The upload server reports errors as a plain string
+        Some(error) => Err(VkError { code: 0, message: error.to_string() }.into()),
+        None => Ok(()),
+    }
+}
+
+// Progress of an upload, kept next to the video so an interrupted upload continues where it
+// stopped on the next run
+#[derive(Serialize, Deserialize)]
+struct UploadState {
+    upload_url: String,
+    session_id: String,
+    file_size: u64,
+    // Bytes the upload server has confirmed
+    offset: u64,
+    owner_id: Option<i64>,
+    video_id: Option<i64>,
+}
+
+impl UploadState {
+    fn path(file_path: &str) -> String {
+        format!("{}.vk-upload", file_path)
+    }
+
+    // A saved state only counts if it was made for the same file
+    fn load(file_path: &str, file_size: u64) -> Option<UploadState> {
+        let content = fs::read_to_string(UploadState::path(file_path)).ok()?;
+        let state: UploadState = serde_json::from_str(&content).ok()?;
+        (state.file_size == file_size).then_some(state)
+    }
 
+    fn save(&self, file_path: &str) -> Result<()> {
+        let path = UploadState::path(file_path);
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_string(self)?).context("Failed to write VK upload state")?;
+        fs::rename(&tmp, &path).context("Failed to replace VK upload state")?;
+        Ok(())
+    }
+
+    fn remove(file_path: &str) {
+        fs::remove_file(UploadState::path(file_path)).ok();
+    }
+}
+
+// Creates the video on VK and returns a fresh upload session for it
+async fn start_upload(client: &Client, access_token: &str, text: &PostText, file_size: u64,
+                      retry: &Retry<'_>) -> Result<UploadState> {
     // Step 1: Get upload URL
     let url = "https://api.vk.com/method/video.save";
-    let res = client.get(url)
-        .query(&[
-            ("access_token", access_token),
-            ("v", "5.131"),
-            ("name", title),
-        ])
-        .send()
-        .await?
-        .json::<Value>()
-        .await?;
+    let res = retry.run("Requesting the upload URL", || async {
+        let res = client.get(url)
+            .query(&[
+                ("access_token", access_token),
+                ("v", "5.131"),
+                ("name", text.title.as_str()),
+                ("description", text.description.as_str()),
+            ])
+            .send()
+            .await?;
+        let res = check_status(res).await?.json::<Value>().await?;
+        check_api_error(&res)?;
+        Ok(res)
+    }).await?;
 
     let upload_url = res["response"]["upload_url"].as_str()
         .ok_or_else(|| anyhow!("Failed to get upload URL"))?
         .to_string();
+    Ok(UploadState {
+        upload_url,
+        session_id: format!("{:016x}", rand::random::<u64>()),
+        file_size,
+        offset: 0,
+        owner_id: res["response"]["owner_id"].as_i64(),
+        video_id: res["response"]["video_id"].as_i64(),
+    })
+}
 
-    // Step 2: Upload video
-    // Create a multipart form with the video file
-    let form = Form::new()
-        .file("video_file", file_path).await?;
+// Sends the file in `Content-Range` chunks, saving the confirmed offset after each one
+async fn send_chunks(client: &Client, file_path: &str, state: &mut UploadState, retry: &Retry<'_>) -> Result<()> {
+    let file_name = Path::new(file_path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video.mp4".to_string());
+
+    let pb = progress::bytes(state.file_size, "Uploading to VK");
+    pb.set_position(state.offset);
 
-    // Step 3: Send video to the upload URL
-    let upload_res = client.post(&upload_url)
-        .multipart(form)
-        .send()
-        .await?;
+    while state.offset < state.file_size {
+        let start = state.offset;
+        let end = (start + CHUNK_SIZE).min(state.file_size) - 1;
+        let len = end - start + 1;
 
-    if !upload_res.status().is_success() {
-        return Err(anyhow!("Failed to upload video to VK. Status: {}", upload_res.status()));
+        let (status, body) = retry.run(&format!("Uploading bytes {}-{}", start, end), || async {
+            let reader = progress::open(file_path, start, len, &pb).await?;
+            let res = client.post(&state.upload_url)
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-Length", len)
+                .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, state.file_size))
+                .header("Session-ID", &state.session_id)
+                .body(progress::body(reader))
+                .send()
+                .await?;
+            let res = check_status(res).await?;
+            Ok((res.status(), res.text().await?))
+        }).await?;
+
+        if status == StatusCode::CREATED {
+            // The server answers with the range it has so far, e.g. "0-16777215/1500000000"
+            state.offset = body.split('/').next()
+                .and_then(|range| range.rsplit('-').next())
+                .and_then(|received| received.trim().parse::<u64>().ok())
+                .map(|received| received + 1)
+                .unwrap_or(end + 1);
+        } else {
+            check_api_error(&serde_json::from_str(&body).unwrap_or_default())?;
+            println!("VK Upload Response: {}", body);
+            state.offset = state.file_size;
+        }
+        state.save(file_path)?;
+        pb.set_position(state.offset);
     }
+    pb.finish_with_message("Upload complete");
+    Ok(())
+}
+
+// Returns the link to the uploaded video
+pub async fn upload_to_vk(access_token: &str, text: &PostText, file_path: &str, retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    let file_size = fs::metadata(file_path)
+        .map(|m| m.len())
+        .context("Failed to get file metadata")?;
 
-    let upload_response_text = upload_res.text().await?;
-    println!("VK Upload Response: {}", upload_response_text);
+    let (mut state, resumed) = match UploadState::load(file_path, file_size) {
+        Some(state) => {
+            println!("Resuming VK upload at {} of {} bytes", state.offset, file_size);
+            (state, true)
+        }
+        None => {
+            let state = start_upload(&client, access_token, text, file_size, retry).await?;
+            state.save(file_path)?;
+            (state, false)
+        }
+    };
 
-    // Optionally, you can handle further steps like finalizing the upload on VK here.
+    // Step 2: Upload video
+    if let Err(e) = send_chunks(&client, file_path, &mut state, retry).await {
+        // An upload server that refuses a resumed session has most likely expired it, so the next
+        // run starts over instead of failing the same way
+        if resumed && !UploadError::classify(retry.platform, &e).retryable() {
+            UploadState::remove(file_path);
+        }
+        return Err(e);
+    }
+    UploadState::remove(file_path);
 
-    Ok(())
+    let url = match (state.owner_id, state.video_id) {
+        (Some(owner_id), Some(video_id)) => Some(format!("https://vk.com/video{}_{}", owner_id, video_id)),
+        _ => None,
+    };
+    Ok(url)
 }
@@ -0,0 +1,184 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::task::JoinHandle;
+use crate::retry::{check_status, HttpError, RetryPolicy};
+use crate::store::{self, Store};
+
+// Deliveries still in flight; the CLI waits for them before it exits
+static PENDING: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+// Outgoing HTTP calls on job events, so other tools can follow the pipeline
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct WebhooksConfig {
+    pub endpoints: Vec<Endpoint>,
+    pub retry: RetryPolicy,
+    // JSON lines file with the deliveries that failed for good
+    pub dead_letter: String,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            endpoints: Vec::new(),
+            retry: RetryPolicy::default(),
+            dead_letter: "./webhooks-dead-letter.jsonl".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct Endpoint {
+    pub url: String,
+    // Signs the body; the signature is sent as `X-Signature-256: sha256=<hex>` over
+    // `<X-Webhook-Timestamp>.<body>`
+    pub secret: Option<String>,
+    // Events the endpoint receives; empty means all of them
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Event {
+    Queued,
+    Downloaded,
+    Transformed,
+    Uploaded,
+    Failed,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Queued => "queued",
+            Event::Downloaded => "downloaded",
+            Event::Transformed => "transformed",
+            Event::Uploaded => "uploaded",
+            Event::Failed => "failed",
+        }
+    }
+}
+
+// Size and path of a file the payload refers to
+pub(crate) fn file_info(path: &str) -> Value {
+    json!({ "path": path, "size": std::fs::metadata(path).map(|m| m.len()).ok() })
+}
+
+// Sends `event` of job `job_id` to every endpoint that wants it, in the background. `data` is merged
+// into the payload next to the job record from `store_path`.
+pub(crate) fn emit(config: &WebhooksConfig, store_path: &str, job_id: u64, event: Event, data: Value) {
+    let endpoints: Vec<Endpoint> = config.endpoints.iter()
+        .filter(|endpoint| endpoint.events.is_empty() || endpoint.events.contains(&event))
+        .cloned()
+        .collect();
+    if endpoints.is_empty() {
+        return;
+    }
+    let job = Store::load(store_path).ok()
+        .and_then(|store| store.jobs.into_iter().find(|job| job.id == job_id));
+    let mut payload = json!({
+        "event": event,
+        "timestamp": store::now(),
+        "job": job,
+    });
+    if let (Some(payload), Value::Object(data)) = (payload.as_object_mut(), data) {
+        payload.extend(data);
+    }
+    let body = payload.to_string();
+
+    let mut pending = PENDING.lock().expect("webhook list lock");
+    pending.retain(|delivery| !delivery.is_finished());
+    for endpoint in endpoints {
+        let config = config.clone();
+        let body = body.clone();
+        pending.push(tokio::spawn(async move {
+            deliver(&config, &endpoint, event, &body).await;
+        }));
+    }
+}
+
+// Waits for the deliveries that are still being sent or retried
+pub(crate) async fn flush() {
+    let pending = std::mem::take(&mut *PENDING.lock().expect("webhook list lock"));
+    for delivery in pending {
+        delivery.await.ok();
+    }
+}
+
+fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn send(client: &Client, endpoint: &Endpoint, event: Event, body: &str) -> Result<()> {
+    let timestamp = store::now();
+    let mut request = client.post(&endpoint.url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Event", event.name())
+        .header("X-Webhook-Timestamp", timestamp);
+    if let Some(secret) = &endpoint.secret {
+        request = request.header("X-Signature-256", signature(secret, timestamp, body));
+    }
+    check_status(request.body(body.to_string()).send().await?).await?;
+    Ok(())
+}
+
+// Retries with backoff and writes the delivery to the dead-letter log once it has failed for good
+async fn deliver(config: &WebhooksConfig, endpoint: &Endpoint, event: Event, body: &str) {
+    let client = Client::new();
+    let mut attempt = 1;
+    let error = loop {
+        let error = match send(&client, endpoint, event, body).await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        let http = error.downcast_ref::<HttpError>();
+        // Other client errors will not go away by sending the same request again
+        let permanent = http.is_some_and(|e| e.status.is_client_error()
+            && e.status != StatusCode::REQUEST_TIMEOUT && e.status != StatusCode::TOO_MANY_REQUESTS);
+        if permanent || attempt >= config.retry.max_attempts {
+            break error;
+        }
+        let delay = http.and_then(|e| e.retry_after).unwrap_or_else(|| config.retry.backoff(attempt));
+        eprintln!("Webhook {} to {} failed (attempt {}/{}), retrying in {:.0}s: {:#}",
+                  event.name(), endpoint.url, attempt, config.retry.max_attempts, delay.as_secs_f64(), error);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+
+    eprintln!("Webhook {} to {} failed: {:#}", event.name(), endpoint.url, error);
+    let entry = json!({
+        "failed_at": store::now(),
+        "url": endpoint.url,
+        "event": event,
+        "attempts": attempt,
+        "error": format!("{:#}", error),
+        "payload": serde_json::from_str::<Value>(body).unwrap_or(Value::Null),
+    });
+    let written = OpenOptions::new().create(true).append(true).open(&config.dead_letter)
+        .and_then(|mut file| writeln!(file, "{}", entry));
+    if let Err(e) = written {
+        eprintln!("Failed to write the webhook dead-letter log {}: {}", config.dead_letter, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        // HMAC-SHA256 of `1700000000.{"event":"job.done","id":7}` with the key `whsec_test`
+        assert_eq!(signature("whsec_test", 1_700_000_000, r#"{"event":"job.done","id":7}"#),
+                   "sha256=987048e2bc2b24ee095507f35d809410de4981f32642dd12b300d9adb05fe2f0");
+    }
+}
@@ -1,70 +1,197 @@
-use std::{process::Command, time::Duration};
+use std::{path::Path, process::Command};
 use anyhow::{Result, anyhow};
-use indicatif::{ProgressBar, ProgressStyle};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use crate::error::{run_tool, tool_output, DownloadError};
+use crate::progress;
 
-pub fn download_video(url: &str, output: &str) -> Result<(String, String)> {
-    let pb = ProgressBar::new_spinner();
-    pb.set_style(ProgressStyle::default_spinner().template("{spinner} Downloading {msg}")?);
-    pb.enable_steady_tick(Duration::from_millis(100));
+// Everything we keep from yt-dlp's `--dump-json` output
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub(crate) struct VideoMetadata {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub title: String,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub description: String,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub tags: Vec<String>,
+    // YYYYMMDD
+    pub upload_date: Option<String>,
+    pub uploader: Option<String>,
+    pub channel: Option<String>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub chapters: Vec<Chapter>,
+    // Remote thumbnail URL as reported by yt-dlp
+    pub thumbnail: Option<String>,
+    // Local copy of the thumbnail, set once the video has been downloaded
+    #[serde(default)]
+    pub thumbnail_file: Option<String>,
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub webpage_url: String,
+    #[serde(default)]
+    pub is_short: bool,
+    #[serde(rename = "_filename", default)]
+    pub filename: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub(crate) struct Chapter {
+    pub start_time: f64,
+    pub end_time: f64,
+    pub title: String,
+}
+
+// yt-dlp writes `null` for fields a site does not provide
+fn null_as_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+pub(crate) fn download_video(url: &str, output: &str) -> Result<VideoMetadata> {
+    let pb = progress::spinner("Downloading");
 
     // Получаем метаданные с учетом кастомного формата имени файла
-    let (filename, title) = get_video_metadata(url, output)?;
+    let mut metadata = get_video_metadata(url, output)?;
 
     //check if file already exists
-    if std::path::Path::new(&filename).exists() {
+    if Path::new(&metadata.filename).exists() {
         pb.finish_with_message("File already exists");
-        return Ok((filename, title));
+        set_thumbnail_file(&mut metadata);
+        return Ok(metadata);
     }
 
-    let status = Command::new("yt-dlp")
+    let (status, stderr) = run_tool(Command::new("yt-dlp")
         .args([
             "-o",
             &format!("{}/%(title)s.%(ext)s", output),
             "-f", "bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]",
             "--merge-output-format", "mp4",
             "--recode-video", "mp4",
+            "--write-thumbnail",
+            "--convert-thumbnails", "jpg",
+            "--no-playlist",
             url,
-        ])
-        .status()?;
+        ]))
+        .map_err(DownloadError::from)?;
 
     pb.finish_with_message("Download complete");
 
     if !status.success() {
-        return Err(anyhow!("Failed to download video"));
+        return Err(DownloadError::from_stderr(&stderr).into());
     }
 
-    Ok((filename, title))
+    set_thumbnail_file(&mut metadata);
+    Ok(metadata)
 }
 
-pub fn get_video_metadata(url: &str, output: &str) -> Result<(String, String)> {
+// yt-dlp stores the thumbnail next to the video under the same name
+fn set_thumbnail_file(metadata: &mut VideoMetadata) {
+    let thumbnail = Path::new(&metadata.filename).with_extension("jpg");
+    if thumbnail.exists() {
+        metadata.thumbnail_file = Some(thumbnail.to_string_lossy().to_string());
+    }
+}
+
+pub(crate) fn get_video_metadata(url: &str, output: &str) -> Result<VideoMetadata> {
     // Вызываем yt-dlp с нужным форматом имени файла
-    let output_data = Command::new("yt-dlp")
+    let output_data = tool_output(Command::new("yt-dlp")
         .arg("--dump-json")
+        .arg("--no-playlist")
         .arg("-o")
         .arg(format!("{}/%(title)s.%(ext)s", output))
         .arg(url)
         .arg("-f")
-        .arg("bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]")
-        .output()?;
+        .arg("bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]"))
+        .map_err(DownloadError::from)?;
 
     if !output_data.status.success() {
-        return Err(anyhow!("Failed to fetch video metadata"));
+        let stderr = String::from_utf8_lossy(&output_data.stderr);
+        return Err(anyhow::Error::new(DownloadError::from_stderr(&stderr)).context("Failed to fetch video metadata"));
     }
 
     // Парсим JSON
     let json_str = String::from_utf8_lossy(&output_data.stdout);
     let json: Value = serde_json::from_str(&json_str).map_err(|e| anyhow!("Failed to parse JSON: {}", e))?;
+    let mut metadata: VideoMetadata = serde_json::from_value(json.clone())
+        .map_err(|e| anyhow!("Failed to parse video metadata: {}", e))?;
 
-    // Получаем название и путь к файлу
-    let mut title = json["title"].as_str().unwrap_or("Unknown Title").to_string();
-    let filename = json["_filename"].as_str().unwrap_or("").to_string();
-
-    if filename.is_empty() {
+    if metadata.filename.is_empty() {
         return Err(anyhow!("Failed to determine filename"));
     }
+    if metadata.title.is_empty() {
+        metadata.title = "Unknown Title".to_string();
+    }
+    if metadata.webpage_url.is_empty() {
+        metadata.webpage_url = url.to_string();
+    }
 
-    title = title.replace(" #shortvideo", "");
+    // Newer yt-dlp versions report shorts as a media type, older ones only through the URL
+    metadata.is_short = json["media_type"].as_str() == Some("short")
+        || metadata.webpage_url.contains("/shorts/")
+        || url.contains("/shorts/");
 
-    Ok((filename, title))
+    Ok(metadata)
+}
+
+// Channel and playlist URLs are expanded into one job per video. Watch links that merely
+// carry a `list=` parameter still refer to a single video.
+pub(crate) fn is_playlist_url(url: &str) -> bool {
+    let playlist_regex = Regex::new(r"[?&]list=|/playlist\b|/@[^/]+|/channel/|/c/|/user/").unwrap();
+    !url.contains("watch?v=") && !url.contains("youtu.be/") && !url.contains("/shorts/")
+        && playlist_regex.is_match(url)
+}
+
+// One entry of a channel or playlist as listed by `yt-dlp --flat-playlist`
+pub(crate) struct PlaylistEntry {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub duration: Option<f64>,
+    pub upload_date: Option<String>,
+    pub is_short: bool,
+}
+
+pub(crate) fn list_playlist(url: &str) -> Result<Vec<PlaylistEntry>> {
+    let output = Command::new("yt-dlp")
+        .arg("--flat-playlist")
+        .arg("--dump-json")
+        .arg(url)
+        .output()
+        .map_err(DownloadError::from)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::Error::new(DownloadError::from_stderr(&stderr))
+            .context(format!("Failed to list playlist: {}", url)));
+    }
+
+    // yt-dlp prints one JSON object per line, one line per entry
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut entries = Vec::new();
+    for line in stdout.lines().filter(|l| !l.trim().is_empty()) {
+        let json: Value = serde_json::from_str(line).map_err(|e| anyhow!("Failed to parse JSON: {}", e))?;
+        let Some(id) = json["id"].as_str() else {
+            continue;
+        };
+        let url = json["url"].as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("https://www.youtube.com/watch?v={}", id));
+        entries.push(PlaylistEntry {
+            id: id.to_string(),
+            title: json["title"].as_str().unwrap_or("").to_string(),
+            is_short: url.contains("/shorts/"),
+            url,
+            duration: json["duration"].as_f64(),
+            upload_date: json["upload_date"].as_str().map(str::to_string),
+        });
+    }
+    Ok(entries)
 }
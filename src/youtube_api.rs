@@ -0,0 +1,240 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use futures_util::FutureExt;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::resumable::Resumable;
+use crate::retry::{check_status, Retry};
+use crate::template::PostText;
+use crate::youtube::VideoMetadata;
+
+// YouTube takes chunks in multiples of 256 KiB
+const CHUNK_SIZE: u64 = 32 * 256 * 1024;
+// Limit on all tags together, counted with the separating commas
+const MAX_TAGS_LENGTH: usize = 500;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct YoutubeConfig {
+    // OAuth client of a Google Cloud project with the YouTube Data API enabled
+    pub client_id: String,
+    pub client_secret: String,
+    // Granted once for the channel with the `youtube.upload` scope, or `youtube` for playlists
+    pub refresh_token: String,
+    #[serde(default = "default_token_url")]
+    pub token_url: String,
+    // Both base URLs can be pointed at a stand-in server
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    // IDs from `videoCategories.list`; 22 is People & Blogs
+    #[serde(default = "default_category_id")]
+    pub category_id: String,
+    #[serde(default)]
+    pub privacy: Privacy,
+    // Keeps the video private and has YouTube publish it this long after the upload, unless the
+    // job gives a time of its own with `--publish-at` or a posting slot
+    pub publish_delay_minutes: Option<i64>,
+    #[serde(default)]
+    pub made_for_kids: bool,
+    // Playlist the video is added to
+    pub playlist_id: Option<String>,
+}
+
+fn default_token_url() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+fn default_api_url() -> String {
+    "https://www.googleapis.com".to_string()
+}
+
+fn default_category_id() -> String {
+    "22".to_string()
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Privacy {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+}
+
+impl Privacy {
+    fn name(self) -> &'static str {
+        match self {
+            Privacy::Public => "public",
+            Privacy::Unlisted => "unlisted",
+            Privacy::Private => "private",
+        }
+    }
+}
+
+impl YoutubeConfig {
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.api_url.trim_end_matches('/'), path)
+    }
+}
+
+// Trades the refresh token for a short-lived access token
+async fn access_token(client: &Client, config: &YoutubeConfig, retry: &Retry<'_>) -> Result<String> {
+    let token = retry.run("Requesting an access token", || async {
+        let res = client.post(&config.token_url)
+            .form(&[
+                ("client_id", config.client_id.as_str()),
+                ("client_secret", config.client_secret.as_str()),
+                ("refresh_token", config.refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+            ])
+            .send()
+            .await?;
+        Ok(check_status(res).await?.json::<Value>().await?)
+    }).await?;
+    token["access_token"].as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("No access_token in the Google token response"))
+}
+
+// YouTube rejects angle brackets in titles and descriptions
+fn clean(text: &str) -> String {
+    text.replace('<', "‹").replace('>', "›")
+}
+
+// As many tags as fit in the limit; YouTube counts a tag with spaces as if it were quoted
+fn tags(metadata: &VideoMetadata) -> Vec<String> {
+    let mut length = 0;
+    let mut tags = Vec::new();
+    for tag in metadata.tags.iter().map(|tag| clean(tag.trim())).filter(|tag| !tag.is_empty()) {
+        let size = tag.chars().count() + if tag.contains(' ') { 2 } else { 0 };
+        let separator = if tags.is_empty() { 0 } else { 1 };
+        if length + separator + size > MAX_TAGS_LENGTH {
+            break;
+        }
+        length += separator + size;
+        tags.push(tag);
+    }
+    tags
+}
+
+fn details(config: &YoutubeConfig, metadata: &VideoMetadata, text: &PostText, publish_at: Option<i64>) -> Value {
+    let mut status = json!({
+        "privacyStatus": config.privacy.name(),
+        "selfDeclaredMadeForKids": config.made_for_kids,
+    });
+    let publish_at = match publish_at {
+        Some(time) => DateTime::from_timestamp(time, 0),
+        None => config.publish_delay_minutes.map(|delay| Utc::now() + Duration::minutes(delay)),
+    };
+    // A time that has passed already means right away
+    if let Some(publish_at) = publish_at.filter(|time| *time > Utc::now()) {
+        // YouTube only schedules private videos
+        status["privacyStatus"] = json!("private");
+        status["publishAt"] = json!(publish_at.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    json!({
+        "snippet": {
+            "title": clean(&text.title),
+            "description": clean(&text.description),
+            "tags": tags(metadata),
+            "categoryId": config.category_id,
+        },
+        "status": status,
+    })
+}
+
+// Creates the upload with the video's details and returns the URL its bytes are sent to
+async fn start_upload(client: &Client, config: &YoutubeConfig, token: &str, file_size: u64, details: &Value,
+                      retry: &Retry<'_>) -> Result<String> {
+    retry.run("Starting the upload", || async {
+        let res = client.post(config.url("upload/youtube/v3/videos"))
+            .query(&[("uploadType", "resumable"), ("part", "snippet,status")])
+            .bearer_auth(token)
+            .header("X-Upload-Content-Length", file_size)
+            .header("X-Upload-Content-Type", "video/*")
+            .json(details)
+            .send()
+            .await?;
+        let res = check_status(res).await?;
+        res.headers().get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("YouTube did not return an upload URL"))
+    }).await
+}
+
+async fn set_thumbnail(client: &Client, config: &YoutubeConfig, token: &str, video_id: &str, thumbnail: &str,
+                       retry: &Retry<'_>) -> Result<()> {
+    let contents = fs::read(thumbnail).context(format!("Failed to read thumbnail: {}", thumbnail))?;
+    let content_type = match Path::new(thumbnail).extension().and_then(|e| e.to_str()) {
+        Some("png") => "image/png",
+        _ => "image/jpeg",
+    };
+    retry.run("Setting the thumbnail", || async {
+        let res = client.post(config.url("upload/youtube/v3/thumbnails/set"))
+            .query(&[("videoId", video_id)])
+            .bearer_auth(token)
+            .header("Content-Type", content_type)
+            .body(contents.clone())
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }).await
+}
+
+async fn add_to_playlist(client: &Client, config: &YoutubeConfig, token: &str, video_id: &str, playlist_id: &str,
+                         retry: &Retry<'_>) -> Result<()> {
+    retry.run("Adding the video to the playlist", || async {
+        let res = client.post(config.url("youtube/v3/playlistItems"))
+            .query(&[("part", "snippet")])
+            .bearer_auth(token)
+            .json(&json!({
+                "snippet": {
+                    "playlistId": playlist_id,
+                    "resourceId": { "kind": "youtube#video", "videoId": video_id },
+                },
+            }))
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }).await
+}
+
+// Returns the link to the uploaded video. With `publish_at`, YouTube keeps the video private until then.
+pub async fn upload_to_youtube(config: &YoutubeConfig, file_path: &str, metadata: &VideoMetadata, text: &PostText,
+                               publish_at: Option<i64>, retry: &Retry<'_>) -> Result<Option<String>> {
+    let client = Client::new();
+    let file_size = fs::metadata(file_path)
+        .map(|m| m.len())
+        .context("Failed to get file metadata")?;
+
+    let token = access_token(&client, config, retry).await?;
+    let details = details(config, metadata, text, publish_at);
+    // Access tokens last an hour, which a large upload can outlast
+    let refresh = || access_token(&client, config, retry).boxed();
+    let video = Resumable { client: &client, token: &token, refresh: Some(&refresh), retry, name: "YouTube",
+                            chunk_size: CHUNK_SIZE }
+        .upload(file_path, || start_upload(&client, config, &token, file_size, &details, retry))
+        .await?;
+    println!("YouTube Upload Response: {}", video);
+    let video_id = video["id"].as_str().ok_or_else(|| anyhow!("No video ID in {}", video))?;
+    let token = access_token(&client, config, retry).await?;
+
+    // The video is out already, a missing cover or playlist entry is not worth failing it
+    if let Some(thumbnail) = &metadata.thumbnail_file {
+        if let Err(e) = set_thumbnail(&client, config, &token, video_id, thumbnail, retry).await {
+            eprintln!("Failed to set the YouTube thumbnail: {:#}", e);
+        }
+    }
+    if let Some(playlist_id) = &config.playlist_id {
+        if let Err(e) = add_to_playlist(&client, config, &token, video_id, playlist_id, retry).await {
+            eprintln!("Failed to add the video to YouTube playlist {}: {:#}", playlist_id, e);
+        }
+    }
+
+    Ok(Some(format!("https://www.youtube.com/watch?v={}", video_id)))
+}